@@ -0,0 +1,171 @@
+//! Verification of an existing output directory
+
+use crate::{Location, RuntimeFormat, lines::RecordDelimiter, tree::Error};
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+
+/// A way in which a line in an output directory is out of place
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Problem {
+    /// The format could not compute a key (or path) for the line
+    InvalidKey,
+    /// The line has the same key as the line before it
+    Repeated,
+    /// The line's key sorts before the key of the line before it
+    Unordered,
+    /// The format places the line in a different file
+    WrongFile {
+        #[serde(serialize_with = "crate::report::serialize_path")]
+        expected: PathBuf,
+    },
+    /// The range of keys in the line's file overlaps the range in another file
+    Overlap {
+        #[serde(serialize_with = "crate::report::serialize_path")]
+        other: PathBuf,
+    },
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Violation {
+    pub location: Location,
+    pub problem: Problem,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct CheckReport {
+    pub file_count: usize,
+    /// Records read (not including any headers)
+    pub line_count: usize,
+    pub violations: Vec<Violation>,
+}
+
+impl CheckReport {
+    pub fn is_valid(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+/// The smallest and largest keys in a file, with the location of the smallest
+struct KeyRange {
+    min: Vec<u8>,
+    max: Vec<u8>,
+    location: Location,
+}
+
+/// Check that an output directory is sorted, unique and partitioned as the format requires
+///
+/// Each file's keys must be strictly increasing and each line must be in the file given by the
+/// format's path for its key. If `check_ranges` is set, no two files may have overlapping key
+/// ranges either, which only holds for formats whose paths partition keys into ranges.
+/// Provenance sidecars are ignored.
+pub fn check<F: RuntimeFormat, P: AsRef<Path>>(
+    format: &F,
+    output: P,
+    record_delimiter: Option<RecordDelimiter>,
+    check_ranges: bool,
+) -> Result<CheckReport, Error<F::Error>> {
+    let delimiter = record_delimiter.unwrap_or_else(|| format.record_delimiter());
+    let files = crate::tree::output_files(output)?;
+
+    let mut report = CheckReport {
+        file_count: files.len(),
+        ..Default::default()
+    };
+    let mut ranges = vec![];
+
+    for file in files {
+        let mut previous: Option<Vec<u8>> = None;
+        let mut range: Option<KeyRange> = None;
+
+        for record in file.records(format, delimiter)? {
+            let record = record?;
+            let location = Location::new(&file.path, record.line_number, record.byte_offset);
+            report.line_count += 1;
+
+            let Ok(key) = crate::store::line_key(format, &record.bytes) else {
+                report.violations.push(Violation {
+                    location,
+                    problem: Problem::InvalidKey,
+                });
+                continue;
+            };
+
+            match previous.as_ref().map(|previous| key.cmp(previous)) {
+                Some(Ordering::Equal) => report.violations.push(Violation {
+                    location: location.clone(),
+                    problem: Problem::Repeated,
+                }),
+                Some(Ordering::Less) => report.violations.push(Violation {
+                    location: location.clone(),
+                    problem: Problem::Unordered,
+                }),
+                _ => {}
+            }
+
+            match format.path(&key) {
+                Ok(expected) => {
+                    if expected != file.relative {
+                        report.violations.push(Violation {
+                            location: location.clone(),
+                            problem: Problem::WrongFile { expected },
+                        });
+                    }
+                }
+                Err(_) => report.violations.push(Violation {
+                    location: location.clone(),
+                    problem: Problem::InvalidKey,
+                }),
+            }
+
+            match range.as_mut() {
+                Some(range) => {
+                    if key < range.min {
+                        range.min.clone_from(&key);
+                        range.location = location;
+                    } else if key > range.max {
+                        range.max.clone_from(&key);
+                    }
+                }
+                None => {
+                    range = Some(KeyRange {
+                        min: key.clone(),
+                        max: key.clone(),
+                        location,
+                    });
+                }
+            }
+
+            previous = Some(key);
+        }
+
+        if check_ranges {
+            ranges.extend(range);
+        }
+    }
+
+    ranges.sort_by(|a, b| a.min.cmp(&b.min));
+
+    // Compare each range with the earlier range that extends furthest
+    let mut widest: Option<&KeyRange> = None;
+
+    for range in &ranges {
+        if let Some(widest) = widest
+            && range.min <= widest.max
+        {
+            report.violations.push(Violation {
+                location: range.location.clone(),
+                problem: Problem::Overlap {
+                    other: widest.location.path.clone(),
+                },
+            });
+        }
+
+        if widest.is_none_or(|widest| range.max > widest.max) {
+            widest = Some(range);
+        }
+    }
+
+    Ok(report)
+}
@@ -1,6 +1,26 @@
-use crate::{Format, report::RunReport, session::FileOrder};
-use clap::{Arg, ArgAction, ArgMatches, Command};
-use std::path::PathBuf;
+use crate::{
+    Format, Repeat, RuntimeFormat, Static,
+    check::{CheckReport, Problem},
+    format::{FormatRegistry, registry::BoxError},
+    lines::RecordDelimiter,
+    policy::{CollisionPolicy, Precedence, Utf8Policy},
+    report::{RepeatSink, RunReport},
+    session::{FileOrder, OutputCodec, Progress, Session},
+    store::Backend,
+    tree::{DiffReport, Difference, TreeStats},
+};
+#[cfg(feature = "rocksdb")]
+use clap::parser::ValueSource;
+use clap::{
+    Arg, ArgAction, ArgMatches, Command,
+    builder::{PossibleValue, PossibleValuesParser},
+};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error<F> {
@@ -14,7 +34,45 @@ pub enum Error<F> {
     Matches(#[from] clap::parser::MatchesError),
     #[error("Arguments error")]
     Args(#[from] clap::error::Error<clap::error::RichFormatter>),
+    #[error("Report error")]
+    Report(#[from] serde_json::Error),
+    #[error("Output directory error")]
+    Tree(#[from] crate::tree::Error<F>),
+    #[error("Invalid hex key")]
+    InvalidKey(String),
 }
+
+/// The result of the subcommand that was run
+#[derive(Debug)]
+pub enum Outcome {
+    Sort(RunReport),
+    Merge(RunReport),
+    Check(CheckReport),
+    Stats(TreeStats),
+    /// The number of keys that were and were not found (the lines are written to standard output)
+    Query {
+        found: usize,
+        missing: usize,
+    },
+    /// The differences are written to standard output
+    Diff(DiffReport),
+    /// A subcommand added with [`App::with_command`], with its name and arguments
+    Custom(String, ArgMatches),
+    /// The registered formats were written to standard output
+    ListFormats,
+}
+
+/// The contents of the file written by `--report`
+#[derive(Serialize)]
+struct ReportFile<'a> {
+    /// The command-line arguments, by name
+    parameters: Map<String, Value>,
+    #[serde(flatten)]
+    report: &'a RunReport,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repeats: Option<Vec<Repeat>>,
+}
+
 pub struct App {
     command: Command,
 }
@@ -22,98 +80,738 @@ pub struct App {
 impl App {
     pub fn new(name: &str) -> Self {
         let command = Command::new(name.to_string())
-            .arg(
-                Arg::new("input")
-                    .long("input")
-                    .short('i')
-                    .value_parser(clap::value_parser!(PathBuf))
-                    .required(true)
-                    .help("Input path"),
+            .subcommand_required(true)
+            .arg_required_else_help(true)
+            .subcommand(
+                Command::new("sort")
+                    .about("Sort and de-duplicate the inputs into an output directory")
+                    .arg(
+                        Arg::new("input")
+                            .long("input")
+                            .short('i')
+                            .value_parser(clap::value_parser!(PathBuf))
+                            .action(ArgAction::Append)
+                            .required_unless_present("files-from")
+                            .help("Input file or directory path (may be repeated, or - for stdin)"),
+                    )
+                    .arg(
+                        Arg::new("files-from")
+                            .long("files-from")
+                            .value_parser(clap::value_parser!(PathBuf))
+                            .help("File containing input paths, one per line (or - for stdin)"),
+                    )
+                    .arg(
+                        Arg::new("by-size")
+                            .long("by-size")
+                            .help("Sort input files by size")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        Arg::new("update")
+                            .long("update")
+                            .help("Merge the inputs into the existing contents of the output directory")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        Arg::new("deterministic")
+                            .long("deterministic")
+                            .help("Resolve repeated keys by input order instead of read order")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .args(Self::session_args()),
             )
-            .arg(
-                Arg::new("output")
-                    .long("output")
-                    .short('o')
-                    .value_parser(clap::value_parser!(PathBuf))
-                    .required(true)
-                    .help("Output directory path"),
+            .subcommand(
+                Command::new("merge")
+                    .about("Merge output directories into a new output directory (later directories take precedence)")
+                    .arg(
+                        Arg::new("tree")
+                            .value_parser(clap::value_parser!(PathBuf))
+                            .num_args(1..)
+                            .required(true)
+                            .help("Output directory paths to merge"),
+                    )
+                    .args(Self::session_args()),
             )
-            .arg(
-                Arg::new("tmp")
-                    .long("tmp")
-                    .short('t')
-                    .value_parser(clap::value_parser!(PathBuf))
-                    .default_value("/tmp/")
-                    .help("Temporary database directory base path"),
+            .subcommand(
+                Command::new("check")
+                    .about("Verify that an output directory is sorted, unique and partitioned")
+                    .arg(Self::tree_arg("output", "Output directory path"))
+                    .arg(Self::records_arg())
+                    .arg(
+                        Arg::new("check-ranges")
+                            .long("check-ranges")
+                            .help("Also require the key ranges of the files not to overlap")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        Arg::new("report")
+                            .long("report")
+                            .value_parser(clap::value_parser!(PathBuf))
+                            .help("Write the check report to a JSON file"),
+                    ),
             )
-            .arg(
-                Arg::new("by-size")
-                    .long("by-size")
-                    .help("Sort input files by size")
-                    .action(ArgAction::SetTrue),
+            .subcommand(
+                Command::new("stats")
+                    .about("Count the lines and bytes in each file of an output directory")
+                    .arg(Self::tree_arg("output", "Output directory path"))
+                    .arg(Self::records_arg())
+                    .arg(
+                        Arg::new("report")
+                            .long("report")
+                            .value_parser(clap::value_parser!(PathBuf))
+                            .help("Write the statistics to a JSON file"),
+                    ),
             )
-            .arg(
-                Arg::new("parallel")
-                    .long("parallel")
-                    .short('p')
-                    .value_parser(clap::value_parser!(usize))
-                    .default_value("8")
-                    .help("Parallelism"),
+            .subcommand(
+                Command::new("query")
+                    .about("Print the lines for keys from an output directory")
+                    .arg(Self::tree_arg("output", "Output directory path"))
+                    .arg(
+                        Arg::new("key")
+                            .num_args(1..)
+                            .required(true)
+                            .help("Lines whose keys to look up (or hex keys, with --raw-key)"),
+                    )
+                    .arg(
+                        Arg::new("raw-key")
+                            .long("raw-key")
+                            .help(
+                                "Use the arguments as hex-encoded keys (as in the collision \
+                                history) instead of computing keys from them",
+                            )
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(Self::records_arg()),
             )
-            .arg(
-                Arg::new("zstd")
-                    .long("zstd")
-                    .short('z')
-                    .value_parser(clap::value_parser!(u8))
-                    .help("Compress output (ZSTD)"),
+            .subcommand(
+                Command::new("diff")
+                    .about("Print the keys whose lines differ between two output directories")
+                    .arg(Self::tree_arg("left", "Old output directory path"))
+                    .arg(Self::tree_arg("right", "New output directory path"))
+                    .arg(Self::records_arg()),
             );
 
         Self { command }
     }
 
-    pub async fn run_from_args<F: Format + Clone + Send + 'static>(
+    /// Arguments for the subcommands that run a session
+    fn session_args() -> Vec<Arg> {
+        let args = vec![
+            Arg::new("output")
+                .long("output")
+                .short('o')
+                .value_parser(clap::value_parser!(PathBuf))
+                .required(true)
+                .help("Output directory path"),
+            Arg::new("tmp")
+                .long("tmp")
+                .short('t')
+                .value_parser(clap::value_parser!(PathBuf))
+                .default_value("/tmp/")
+                .help("Temporary database directory base path"),
+            Arg::new("store")
+                .long("store")
+                .value_parser(Backend::NAMES)
+                .default_value(Backend::NAMES[0])
+                .help("Storage backend"),
+            Arg::new("parallel")
+                .long("parallel")
+                .short('p')
+                .value_parser(clap::value_parser!(usize))
+                .default_value("8")
+                .help("Parallelism"),
+            Arg::new("zstd")
+                .long("zstd")
+                .short('z')
+                .value_parser(clap::value_parser!(u8))
+                .help("Compress output (ZSTD)"),
+            Arg::new("on-collision")
+                .long("on-collision")
+                .value_parser(CollisionPolicy::NAMES)
+                .default_value("last")
+                .help("Value to keep when lines share a key"),
+            Arg::new("provenance")
+                .long("provenance")
+                .help("Write the input location of each output line to a sidecar file")
+                .action(ArgAction::SetTrue),
+            Arg::new("collisions")
+                .long("collisions")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Write every distinct line of each colliding key to a JSON lines file"),
+            Arg::new("repeats-out")
+                .long("repeats-out")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Write repeated keys to a ZSTD-compressed JSON lines file"),
+            Arg::new("report")
+                .long("report")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Write the run report to a JSON file"),
+            Arg::new("report-repeats")
+                .long("report-repeats")
+                .requires("report")
+                .conflicts_with("repeats-out")
+                .help("Include repeated keys in the JSON run report")
+                .action(ArgAction::SetTrue),
+            Arg::new("invalid-utf8")
+                .long("invalid-utf8")
+                .value_parser(Utf8Policy::NAMES)
+                .default_value("error")
+                .help("Handling of lines that are not valid UTF-8"),
+            Self::records_arg(),
+        ];
+
+        #[cfg(feature = "rocksdb")]
+        let args = {
+            let mut args = args;
+            args.extend([
+                Arg::new("db")
+                    .long("db")
+                    .value_parser(clap::value_parser!(PathBuf))
+                    .help("Persistent database directory path (resumes an interrupted run)"),
+                Arg::new("engine")
+                    .long("engine")
+                    .value_parser(crate::db::Engine::NAMES)
+                    .default_value("transaction")
+                    .help("Database loading engine (bulk always resolves repeats in input order)"),
+                Arg::new("db-compression")
+                    .long("db-compression")
+                    .value_parser(crate::db::DbCompression::NAMES)
+                    .default_value("none")
+                    .help("Database file compression"),
+                Arg::new("db-write-buffer")
+                    .long("db-write-buffer")
+                    .value_parser(Self::parse_mebibytes)
+                    .help("Database memtable size (MiB)"),
+                Arg::new("db-block-cache")
+                    .long("db-block-cache")
+                    .value_parser(Self::parse_mebibytes)
+                    .help("Database block cache size (MiB)"),
+                Arg::new("db-memory-budget")
+                    .long("db-memory-budget")
+                    .value_parser(Self::parse_mebibytes)
+                    .help("Total database memtable and block cache size (MiB)"),
+                Arg::new("db-background-jobs")
+                    .long("db-background-jobs")
+                    .value_parser(clap::value_parser!(i32))
+                    .help("Maximum concurrent database flushes and compactions"),
+                Arg::new("db-disable-wal")
+                    .long("db-disable-wal")
+                    .help("Write to the database without the write-ahead log")
+                    .action(ArgAction::SetTrue),
+            ]);
+            args
+        };
+
+        args
+    }
+
+    fn tree_arg(id: &'static str, help: &'static str) -> Arg {
+        Arg::new(id)
+            .value_parser(clap::value_parser!(PathBuf))
+            .required(true)
+            .help(help)
+    }
+
+    fn records_arg() -> Arg {
+        Arg::new("records")
+            .long("records")
+            .value_parser(RecordDelimiter::NAMES)
+            .help("Record delimiter (defaults to the format's delimiter)")
+    }
+
+    pub async fn run_from_args<F: Format + 'static>(self) -> Result<Outcome, Error<F::Error>>
+    where
+        F::Error: Send,
+    {
+        self.run_from_args_with(|_| Ok(Static::<F>::new())).await
+    }
+
+    /// Run with a format instance built from the parsed arguments
+    ///
+    /// Applications can add their own format parameters (before the subcommand) or subcommands
+    /// with [`App::with_command`].
+    pub async fn run_from_args_with<
+        F: RuntimeFormat + Clone + Send + 'static,
+        M: FnOnce(&ArgMatches) -> Result<F, F::Error>,
+    >(
         self,
-    ) -> Result<RunReport, Error<F::Error>>
+        make_format: M,
+    ) -> Result<Outcome, Error<F::Error>>
     where
         F::Error: Send,
     {
         let matches = self.command.get_matches();
-        Self::run_from_matches::<F>(&matches).await
+        let format = make_format(&matches).map_err(Error::Format)?;
+
+        Self::run_subcommand(format, &matches).await
+    }
+
+    /// Run with a format chosen by name with `--format`
+    ///
+    /// `--list-formats` prints the name and description of each registered format instead.
+    pub async fn run_from_args_with_registry(
+        self,
+        registry: &FormatRegistry,
+    ) -> Result<Outcome, Error<BoxError>> {
+        let command = self
+            .command
+            .subcommand_required(false)
+            .arg(
+                Arg::new("format")
+                    .long("format")
+                    .value_parser(PossibleValuesParser::new(
+                        registry
+                            .descriptions()
+                            .into_iter()
+                            .map(|(name, description)| {
+                                PossibleValue::new(name.to_string()).help(description.to_string())
+                            }),
+                    ))
+                    .required_unless_present("list-formats")
+                    .help("Format of the lines"),
+            )
+            .arg(
+                Arg::new("list-formats")
+                    .long("list-formats")
+                    .help("Print the available formats")
+                    .action(ArgAction::SetTrue),
+            );
+
+        let matches = command.get_matches();
+
+        if matches.get_flag("list-formats") {
+            let mut writer = BufWriter::new(std::io::stdout().lock());
+            for (name, description) in registry.descriptions() {
+                writeln!(writer, "{name}\t{description}")?;
+            }
+            writer.flush()?;
+
+            return Ok(Outcome::ListFormats);
+        }
+
+        let name = matches.try_get_one::<String>("format")?.unwrap();
+        let format = registry.make(name, &matches).map_err(Error::Format)?;
+
+        Self::run_subcommand(format, &matches).await
+    }
+
+    async fn run_subcommand<F: RuntimeFormat + Clone + Send + 'static>(
+        format: F,
+        matches: &ArgMatches,
+    ) -> Result<Outcome, Error<F::Error>>
+    where
+        F::Error: Send,
+    {
+        match matches.subcommand() {
+            Some(("sort", matches)) => Ok(Outcome::Sort(Self::sort(format, matches).await?)),
+            Some(("merge", matches)) => Ok(Outcome::Merge(Self::merge(format, matches).await?)),
+            Some(("check", matches)) => Ok(Outcome::Check(Self::check(&format, matches)?)),
+            Some(("stats", matches)) => Ok(Outcome::Stats(Self::stats(&format, matches)?)),
+            Some(("query", matches)) => Self::query(&format, matches),
+            Some(("diff", matches)) => Ok(Outcome::Diff(Self::diff(&format, matches)?)),
+            Some((name, matches)) => Ok(Outcome::Custom(name.to_string(), matches.clone())),
+            None => Err(Error::Args(clap::Error::new(
+                clap::error::ErrorKind::MissingSubcommand,
+            ))),
+        }
     }
 
-    async fn run_from_matches<F: Format + Clone + Send + 'static>(
+    async fn sort<F: RuntimeFormat + Clone + Send + 'static>(
+        format: F,
         matches: &ArgMatches,
     ) -> Result<RunReport, Error<F::Error>>
     where
         F::Error: Send,
     {
-        let input = matches.try_get_one::<PathBuf>("input")?.unwrap();
-        let output = matches.try_get_one::<PathBuf>("output")?.unwrap();
-        let temp_dir = matches.try_get_one::<PathBuf>("tmp")?.unwrap();
-        let by_size = matches.get_flag("by-size");
-        let parallelism = matches.try_get_one::<usize>("parallel")?.unwrap();
-        let zstd = matches.try_get_one::<u8>("zstd")?;
+        let mut inputs = matches
+            .try_get_many::<PathBuf>("input")?
+            .map(|values| values.cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
 
-        let file_order = if by_size {
+        if let Some(files_from) = matches.try_get_one::<PathBuf>("files-from")? {
+            inputs.extend(Self::read_files_from(files_from)?);
+        }
+
+        let file_order = if matches.get_flag("by-size") {
             FileOrder::BySizeInterspersed
         } else {
             FileOrder::ByName
         };
 
-        let report = crate::session::run::<F, &PathBuf, &PathBuf, &PathBuf>(
-            input,
-            output,
-            temp_dir,
+        let precedence = if matches.get_flag("deterministic") {
+            Precedence::Input
+        } else {
+            Precedence::Arrival
+        };
+
+        Self::run_session(
+            format,
+            matches,
+            inputs,
             file_order,
-            *parallelism,
-            zstd.copied(),
-            true,
+            precedence,
+            matches.get_flag("update"),
+        )
+        .await
+    }
+
+    async fn merge<F: RuntimeFormat + Clone + Send + 'static>(
+        format: F,
+        matches: &ArgMatches,
+    ) -> Result<RunReport, Error<F::Error>>
+    where
+        F::Error: Send,
+    {
+        let mut inputs = vec![];
+
+        for tree in matches
+            .try_get_many::<PathBuf>("tree")?
+            .into_iter()
+            .flatten()
+        {
+            inputs.extend(
+                crate::tree::output_files(tree)?
+                    .into_iter()
+                    .map(|file| file.path),
+            );
+        }
+
+        Self::run_session(
+            format,
+            matches,
+            inputs,
+            FileOrder::AsGiven,
+            Precedence::Input,
+            false,
         )
-        .await?;
+        .await
+    }
+
+    async fn run_session<F: RuntimeFormat + Clone + Send + 'static>(
+        format: F,
+        matches: &ArgMatches,
+        inputs: Vec<PathBuf>,
+        file_order: FileOrder,
+        precedence: Precedence,
+        update: bool,
+    ) -> Result<RunReport, Error<F::Error>>
+    where
+        F::Error: Send,
+    {
+        let output = matches.try_get_one::<PathBuf>("output")?.unwrap();
+        let temp_dir = matches.try_get_one::<PathBuf>("tmp")?.unwrap();
+        let report_path = matches.try_get_one::<PathBuf>("report")?;
+        let reported_repeats = matches
+            .get_flag("report-repeats")
+            .then(|| Arc::new(Mutex::new(vec![])));
+        let repeats = match (
+            matches.try_get_one::<PathBuf>("repeats-out")?,
+            reported_repeats.clone(),
+        ) {
+            (Some(path), _) => RepeatSink::File(path.clone()),
+            (None, Some(reported_repeats)) => RepeatSink::Callback(Arc::new(move |repeat| {
+                if let Ok(mut reported_repeats) = reported_repeats.lock() {
+                    reported_repeats.push(repeat);
+                }
+            })),
+            (None, None) => RepeatSink::Count,
+        };
+        let parallelism = matches.try_get_one::<usize>("parallel")?.unwrap();
+        let codec = matches
+            .try_get_one::<u8>("zstd")?
+            .map_or(OutputCodec::Plain, |level| OutputCodec::Zstd(*level));
+        let collision_policy = matches
+            .try_get_one::<String>("on-collision")?
+            .and_then(|name| CollisionPolicy::from_name(name))
+            .unwrap_or_default();
+        let backend = matches
+            .try_get_one::<String>("store")?
+            .and_then(|name| Backend::from_name(name))
+            .unwrap_or_default();
+
+        // Database options given with another backend are passed on so that they are rejected
+        #[cfg(feature = "rocksdb")]
+        let db_options = if matches!(backend, Backend::RocksDb { .. })
+            || [
+                "db-compression",
+                "db-write-buffer",
+                "db-block-cache",
+                "db-memory-budget",
+                "db-background-jobs",
+                "db-disable-wal",
+            ]
+            .iter()
+            .any(|id| matches.value_source(id) == Some(ValueSource::CommandLine))
+        {
+            Some(Self::db_options(matches)?)
+        } else {
+            None
+        };
+        let utf8_policy = matches
+            .try_get_one::<String>("invalid-utf8")?
+            .and_then(|name| Utf8Policy::from_name(name))
+            .unwrap_or_default();
+
+        let mut builder = Session::builder(format)
+            .with_inputs(inputs)
+            .with_output(output)
+            .with_temp_base(temp_dir)
+            .with_backend(backend)
+            .with_file_order(file_order)
+            .with_parallelism(*parallelism)
+            .with_codec(codec)
+            .with_collision_policy(collision_policy)
+            .with_precedence(precedence)
+            .with_utf8_policy(utf8_policy)
+            .with_update(update)
+            .with_provenance(matches.get_flag("provenance"))
+            .with_repeats(repeats)
+            .with_progress(Progress::Bars);
+
+        #[cfg(feature = "rocksdb")]
+        {
+            if let Some(database) = matches.try_get_one::<PathBuf>("db")? {
+                builder = builder.with_database(database);
+            }
+
+            if let Some(engine) = matches
+                .try_get_one::<String>("engine")?
+                .and_then(|name| crate::db::Engine::from_name(name))
+            {
+                builder = builder.with_engine(engine);
+            }
+
+            if let Some(db_options) = db_options {
+                builder = builder.with_db_options(db_options);
+            }
+        }
+
+        if let Some(record_delimiter) = Self::record_delimiter(matches)? {
+            builder = builder.with_record_delimiter(record_delimiter);
+        }
+
+        if let Some(collisions) = matches.try_get_one::<PathBuf>("collisions")? {
+            builder = builder.with_collisions(collisions);
+        }
+
+        let report = builder.build()?.run().await?;
+
+        if let Some(report_path) = report_path {
+            let repeats =
+                reported_repeats.and_then(|repeats| Arc::into_inner(repeats)?.into_inner().ok());
+
+            Self::write_json(
+                report_path,
+                &ReportFile {
+                    parameters: Self::parameters(matches),
+                    report: &report,
+                    repeats,
+                },
+            )?;
+        }
+
+        Ok(report)
+    }
+
+    fn check<F: RuntimeFormat>(
+        format: &F,
+        matches: &ArgMatches,
+    ) -> Result<CheckReport, Error<F::Error>> {
+        let output = matches.try_get_one::<PathBuf>("output")?.unwrap();
+        let report = crate::check::check(
+            format,
+            output,
+            Self::record_delimiter(matches)?,
+            matches.get_flag("check-ranges"),
+        )?;
+
+        if let Some(report_path) = matches.try_get_one::<PathBuf>("report")? {
+            Self::write_json(report_path, &report)?;
+        }
+
+        Ok(report)
+    }
+
+    fn stats<F: RuntimeFormat>(
+        format: &F,
+        matches: &ArgMatches,
+    ) -> Result<TreeStats, Error<F::Error>> {
+        let output = matches.try_get_one::<PathBuf>("output")?.unwrap();
+        let stats = crate::tree::stats(format, output, Self::record_delimiter(matches)?)?;
+
+        if let Some(report_path) = matches.try_get_one::<PathBuf>("report")? {
+            Self::write_json(report_path, &stats)?;
+        }
+
+        Ok(stats)
+    }
+
+    fn query<F: RuntimeFormat>(
+        format: &F,
+        matches: &ArgMatches,
+    ) -> Result<Outcome, Error<F::Error>> {
+        let output = matches.try_get_one::<PathBuf>("output")?.unwrap();
+        let record_delimiter = Self::record_delimiter(matches)?;
+        let raw_key = matches.get_flag("raw-key");
+        let mut writer = BufWriter::new(std::io::stdout().lock());
+        let mut found = 0;
+        let mut missing = 0;
+
+        // The format may need the header to key the values
+        crate::tree::read_header(format, output, record_delimiter)?;
+
+        for value in matches.try_get_many::<String>("key")?.into_iter().flatten() {
+            let key = if raw_key {
+                decode_hex(value).ok_or_else(|| Error::InvalidKey(value.clone()))?
+            } else {
+                format.key(value).map_err(Error::Format)?
+            };
+
+            match crate::tree::query(format, output, record_delimiter, &key)? {
+                Some(result) => {
+                    writer.write_all(&result.line)?;
+                    writeln!(writer)?;
+                    found += 1;
+                }
+                None => {
+                    missing += 1;
+                }
+            }
+        }
+
+        writer.flush()?;
+
+        Ok(Outcome::Query { found, missing })
+    }
+
+    fn diff<F: RuntimeFormat>(
+        format: &F,
+        matches: &ArgMatches,
+    ) -> Result<DiffReport, Error<F::Error>> {
+        let left = matches.try_get_one::<PathBuf>("left")?.unwrap();
+        let right = matches.try_get_one::<PathBuf>("right")?.unwrap();
+        let mut writer = BufWriter::new(std::io::stdout().lock());
+        let mut written = Ok(());
+
+        let report = crate::tree::diff(
+            format,
+            left,
+            right,
+            Self::record_delimiter(matches)?,
+            |difference| {
+                if written.is_ok() {
+                    written = Self::write_difference(&mut writer, &difference);
+                }
+            },
+        )?;
+
+        written?;
+        writer.flush()?;
 
         Ok(report)
     }
 
+    /// Write a difference with `-` and `+` prefixes for the old and new lines
+    fn write_difference<W: Write>(
+        writer: &mut W,
+        difference: &Difference,
+    ) -> Result<(), std::io::Error> {
+        let (old, new) = match difference {
+            Difference::Removed(old) => (Some(old), None),
+            Difference::Added(new) => (None, Some(new)),
+            Difference::Changed { old, new } => (Some(old), Some(new)),
+        };
+
+        for (prefix, found) in [(b"-\t", old), (b"+\t", new)] {
+            if let Some(found) = found {
+                writer.write_all(prefix)?;
+                writer.write_all(&found.line)?;
+                writeln!(writer)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn record_delimiter(
+        matches: &ArgMatches,
+    ) -> Result<Option<RecordDelimiter>, clap::parser::MatchesError> {
+        Ok(matches
+            .try_get_one::<String>("records")?
+            .and_then(|name| RecordDelimiter::from_name(name)))
+    }
+
+    fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), std::io::Error> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, value)?;
+        writeln!(writer)?;
+        writer.flush()
+    }
+
+    /// The raw values of the arguments that were given or have defaults
+    fn parameters(matches: &ArgMatches) -> Map<String, Value> {
+        matches
+            .ids()
+            .filter_map(|id| {
+                let mut values = matches
+                    .get_raw(id.as_str())?
+                    .map(|value| Value::from(value.to_string_lossy()))
+                    .collect::<Vec<_>>();
+
+                let value = if values.len() == 1 {
+                    values.remove(0)
+                } else {
+                    Value::from(values)
+                };
+
+                Some((id.to_string(), value))
+            })
+            .collect()
+    }
+
+    /// Parse a size in mebibytes as a number of bytes
+    #[cfg(feature = "rocksdb")]
+    fn parse_mebibytes(value: &str) -> Result<usize, String> {
+        value
+            .parse::<usize>()
+            .map_err(|error| error.to_string())?
+            .checked_mul(1 << 20)
+            .ok_or_else(|| format!("{value} MiB is too large"))
+    }
+
+    #[cfg(feature = "rocksdb")]
+    fn db_options(
+        matches: &ArgMatches,
+    ) -> Result<crate::db::DbOptions, clap::parser::MatchesError> {
+        let mebibytes = |name: &str| matches.try_get_one::<usize>(name).map(|size| size.copied());
+
+        Ok(crate::db::DbOptions {
+            compression: matches
+                .try_get_one::<String>("db-compression")?
+                .and_then(|name| crate::db::DbCompression::from_name(name))
+                .unwrap_or_default(),
+            write_buffer_size: mebibytes("db-write-buffer")?,
+            block_cache_size: mebibytes("db-block-cache")?,
+            max_background_jobs: matches.try_get_one::<i32>("db-background-jobs")?.copied(),
+            disable_wal: matches.get_flag("db-disable-wal"),
+            memory_budget: mebibytes("db-memory-budget")?,
+        })
+    }
+
+    fn read_files_from(path: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+        let reader: Box<dyn BufRead> = if crate::lines::is_stdin(path) {
+            Box::new(std::io::stdin().lock())
+        } else {
+            Box::new(BufReader::new(File::open(path)?))
+        };
+
+        reader
+            .lines()
+            .filter(|line| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+            .map(|line| line.map(PathBuf::from))
+            .collect()
+    }
+
     pub fn with_command<U: FnOnce(&Command) -> Command>(&self, f: U) -> Self {
         Self {
             command: f(&self.command),
@@ -131,5 +829,97 @@ impl App {
             report.duplicate_count(),
             report.collision_count()
         );
+
+        if let Some(path) = report.repeats_path.as_ref() {
+            eprintln!("Wrote repeats to {}", path.display());
+        }
+
+        if report.skipped > 0 {
+            eprintln!("Skipped {} invalid lines", report.skipped);
+        }
     }
+
+    pub fn show_check_report(report: &CheckReport) {
+        for violation in &report.violations {
+            let location = &violation.location;
+            let problem = match &violation.problem {
+                Problem::InvalidKey => "invalid key".to_string(),
+                Problem::Repeated => "repeated key".to_string(),
+                Problem::Unordered => "key out of order".to_string(),
+                Problem::WrongFile { expected } => {
+                    format!("line belongs in {}", expected.display())
+                }
+                Problem::Overlap { other } => {
+                    format!("key range overlaps {}", other.display())
+                }
+            };
+
+            eprintln!(
+                "{}:{}: {}",
+                location.path.display(),
+                location.line_number,
+                problem
+            );
+        }
+
+        eprintln!(
+            "Checked {} lines in {} files and found {} violations",
+            report.line_count,
+            report.file_count,
+            report.violations.len()
+        );
+    }
+
+    pub fn show_stats(stats: &TreeStats) {
+        for file in &stats.files {
+            println!(
+                "{}\t{}\t{}\t{}",
+                file.path.display(),
+                file.lines,
+                file.bytes,
+                file.size
+            );
+        }
+
+        eprintln!(
+            "Found {} lines ({} bytes, {} on disk) in {} files",
+            stats.line_count, stats.bytes, stats.size, stats.file_count
+        );
+    }
+
+    pub fn show_diff_report(report: &DiffReport) {
+        eprintln!(
+            "Found {} removed, {} added, {} changed and {} unchanged keys",
+            report.removed, report.added, report.changed, report.unchanged
+        );
+    }
+
+    pub fn show_outcome(outcome: &Outcome) {
+        match outcome {
+            Outcome::Sort(report) | Outcome::Merge(report) => Self::show_run_report(report),
+            Outcome::Check(report) => Self::show_check_report(report),
+            Outcome::Stats(stats) => Self::show_stats(stats),
+            Outcome::Query { found, missing } => {
+                eprintln!("Found {} of {} keys", found, found + missing);
+            }
+            Outcome::Diff(report) => Self::show_diff_report(report),
+            Outcome::Custom(_, _) | Outcome::ListFormats => {}
+        }
+    }
+}
+
+/// Decode a key written in hex, as in the collision history
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..value.len())
+        .step_by(2)
+        .map(|index| {
+            value
+                .get(index..index + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect()
 }
@@ -1,164 +1,849 @@
-use crate::{Format, report::WriteReport};
-use rocksdb::{BlockBasedOptions, DBCompressionType, IteratorMode, Options, TransactionDB};
-use std::collections::HashMap;
-use std::collections::hash_map::Entry;
-use std::fs::File;
-use std::io::{BufWriter, Write};
-use std::marker::PhantomData;
+use crate::{
+    Location, RuntimeFormat,
+    lines::Source,
+    policy::{CollisionPolicy, Precedence},
+    store::{
+        Chunks, CollisionResult, Entries, Error, KeyRepeat, LineResult, LineStore, ManifestEntry,
+        Position, RepeatFn, Resolver, Sources, StoredValue,
+    },
+};
+use rocksdb::{
+    BlockBasedOptions, Cache, ColumnFamily, ColumnFamilyDescriptor, DB, DBCompressionType,
+    DEFAULT_COLUMN_FAMILY_NAME, IngestExternalFileOptions, IteratorMode, Options, SstFileWriter,
+    TransactionDB, TransactionOptions, WriteOptions,
+};
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-
-const ZSTD_EXTENSION: &str = "zst";
-
-#[derive(thiserror::Error, Debug)]
-pub enum Error<F> {
-    #[error("Format error")]
-    Format(F),
-    #[error("I/O error")]
-    Io(#[from] std::io::Error),
-    #[error("UTF-8 decoding error")]
-    Utf8(#[from] std::str::Utf8Error),
-    #[error("RocksDB error")]
-    Db(#[from] rocksdb::Error),
-    #[error("Invalid path for key")]
-    InvalidPath(PathBuf, Vec<u8>),
-    #[error("Invalid database state")]
-    InvalidState,
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+const MANIFEST_CF: &str = "manifest";
+const PROVENANCE_CF: &str = "provenance";
+const HISTORY_CF: &str = "history";
+const SOURCES_CF: &str = "sources";
+const STAGING_DIR_PREFIX: &str = "lines-staging";
+
+/// How lines are loaded into the database
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Engine {
+    /// Each line is inserted in its own transaction
+    ///
+    /// Under [`Precedence::Input`], lines are staged as with [`Engine::Bulk`] instead, so that
+    /// repeated keys are resolved in input order.
+    #[default]
+    Transaction,
+    /// Lines are sorted in chunks, ingested as SST files, and merged after reading
+    ///
+    /// Repeated keys are always resolved in input order.
+    Bulk,
 }
 
-#[derive(Clone)]
-pub struct LineDb<F> {
-    db: Arc<TransactionDB>,
-    _format: PhantomData<F>,
+impl Engine {
+    pub const NAMES: [&'static str; 2] = ["transaction", "bulk"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "transaction" => Some(Self::Transaction),
+            "bulk" => Some(Self::Bulk),
+            _ => None,
+        }
+    }
+}
+
+/// Compression for the database's data files
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum DbCompression {
+    #[default]
+    None,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+impl DbCompression {
+    pub const NAMES: [&'static str; 4] = ["none", "snappy", "lz4", "zstd"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Self::None),
+            "snappy" => Some(Self::Snappy),
+            "lz4" => Some(Self::Lz4),
+            "zstd" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    fn compression_type(self) -> DBCompressionType {
+        match self {
+            Self::None => DBCompressionType::None,
+            Self::Snappy => DBCompressionType::Snappy,
+            Self::Lz4 => DBCompressionType::Lz4,
+            Self::Zstd => DBCompressionType::Zstd,
+        }
+    }
 }
 
-impl<F: Format> LineDb<F> {
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error<F::Error>> {
+/// Tuning options for the line database
+///
+/// Unset sizes use RocksDB's defaults.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DbOptions {
+    pub compression: DbCompression,
+    /// Size of a single memtable in bytes
+    pub write_buffer_size: Option<usize>,
+    /// Size of the block cache in bytes
+    pub block_cache_size: Option<usize>,
+    /// Maximum number of concurrent flushes and compactions
+    pub max_background_jobs: Option<i32>,
+    /// Write lines without the write-ahead log
+    ///
+    /// Every column family is flushed together, so a persistent database can still be resumed
+    /// after an interruption (inputs recorded as complete after the last flush are read again).
+    pub disable_wal: bool,
+    /// Total size in bytes of the memtables and block cache
+    ///
+    /// The budget is split evenly unless the block cache size is also given.
+    pub memory_budget: Option<usize>,
+}
+
+impl DbOptions {
+    fn block_cache_capacity(&self) -> Option<usize> {
+        self.block_cache_size
+            .or(self.memory_budget.map(|budget| budget / 2))
+    }
+
+    /// Whether a memory budget leaves nothing for the memtables once the block cache is taken
+    pub(crate) fn exhausts_memory_budget(&self) -> bool {
+        self.db_write_buffer_size() == Some(0)
+    }
+
+    fn db_write_buffer_size(&self) -> Option<usize> {
+        self.memory_budget
+            .map(|budget| budget.saturating_sub(self.block_cache_capacity().unwrap_or_default()))
+    }
+
+    fn to_options(&self) -> Options {
         let mut options = Options::default();
         options.create_if_missing(true);
+        options.create_missing_column_families(true);
 
         let mut block_options = BlockBasedOptions::default();
         block_options.set_ribbon_filter(10.0);
 
+        if let Some(capacity) = self.block_cache_capacity() {
+            block_options.set_block_cache(&Cache::new_lru_cache(capacity));
+        }
+
         options.set_block_based_table_factory(&block_options);
-        options.set_compression_type(DBCompressionType::None);
+        options.set_compression_type(self.compression.compression_type());
 
-        let db = Arc::new(TransactionDB::open(&options, &Default::default(), path)?);
+        if let Some(size) = self.write_buffer_size {
+            options.set_write_buffer_size(size);
+        }
+
+        if let Some(size) = self.db_write_buffer_size() {
+            options.set_db_write_buffer_size(size);
+        }
+
+        if let Some(jobs) = self.max_background_jobs {
+            options.set_max_background_jobs(jobs);
+        }
+
+        // Writes that skip the write-ahead log are only recovered up to the last flush, which
+        // must cover every column family
+        options.set_atomic_flush(self.disable_wal);
+
+        options
+    }
+}
+
+/// Lines staged for a bulk load, with the inputs whose lines have all been staged
+struct Staging {
+    dir: tempdir::TempDir,
+    /// The options for the staging database and for every SST file
+    options: Options,
+    /// The staging database (replaced after each merge) and its generation
+    db: Mutex<(DB, usize)>,
+    file_count: AtomicUsize,
+    completed: Mutex<Vec<(Source, ManifestEntry)>>,
+}
+
+impl Staging {
+    fn new<E, P: AsRef<Path>>(temp_base: P, options: Options) -> Result<Self, Error<E>> {
+        let dir = tempdir::TempDir::new_in(temp_base, STAGING_DIR_PREFIX)?;
+        let db = Self::open_generation(&options, dir.path(), 0)?;
 
         Ok(Self {
-            db,
-            _format: PhantomData,
+            dir,
+            options,
+            db: Mutex::new((db, 0)),
+            file_count: AtomicUsize::new(0),
+            completed: Mutex::default(),
         })
     }
 
-    /// Insert a line, returning the previous value for the key if one existed
-    pub fn count(&self) -> usize {
-        self.db.iterator(IteratorMode::Start).count()
+    fn open_generation(
+        options: &Options,
+        dir: &Path,
+        generation: usize,
+    ) -> Result<DB, rocksdb::Error> {
+        DB::open(options, dir.join(format!("generation-{generation}")))
     }
 
-    /// Insert a line, returning the previous value for the key if one existed
-    pub fn insert(&self, line: &str) -> Result<Option<Option<String>>, Error<F::Error>> {
-        let key = F::key(line).map_err(Error::Format)?;
-        let tx = self.db.transaction();
-        let value = tx.get_for_update(&key, true)?;
-        let line_bytes = line.as_bytes();
-
-        let result = value
-            .map(|bytes| {
-                Ok(if *bytes != *line_bytes {
-                    Some(std::str::from_utf8(&bytes)?.to_string())
-                } else {
-                    None
-                })
-            })
-            .map_or(
-                Ok(None),
-                |value: Result<Option<String>, Error<F::Error>>| value.map(Some),
-            )?;
+    /// A new path for an SST file in the staging directory
+    fn next_path(&self, name: &str) -> PathBuf {
+        let index = self.file_count.fetch_add(1, Ordering::SeqCst);
+        self.dir.path().join(format!("{name}-{index}.sst"))
+    }
 
-        tx.put(&key, line_bytes)?;
-        tx.commit()?;
+    /// Write sorted entries to an SST file and ingest it
+    fn ingest<E>(&self, entries: &Entries) -> Result<(), Error<E>> {
+        let path = self.next_path("chunk");
+
+        let mut writer = SstFileWriter::create(&self.options);
+        writer.open(&path)?;
+
+        for (key, value) in entries {
+            writer.put(key, value)?;
+        }
+
+        writer.finish()?;
+
+        let db = self.db.lock().map_err(|_| Error::InvalidState)?;
+
+        Ok(db
+            .0
+            .ingest_external_file_opts(&ingest_options(), vec![path])?)
+    }
+}
+
+/// An SST file for a column family, which is only created once an entry is written to it
+struct SstOutput<'a> {
+    cf: &'static str,
+    options: &'a Options,
+    path: PathBuf,
+    writer: Option<SstFileWriter<'a>>,
+}
+
+impl<'a> SstOutput<'a> {
+    fn new(staging: &Staging, cf: &'static str, options: &'a Options) -> Self {
+        Self {
+            cf,
+            options,
+            path: staging.next_path(cf),
+            writer: None,
+        }
+    }
+
+    /// Write an entry (keys must be written in order)
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), rocksdb::Error> {
+        let writer = match self.writer.as_mut() {
+            Some(writer) => writer,
+            None => {
+                let writer = SstFileWriter::create(self.options);
+                writer.open(&self.path)?;
+                self.writer.insert(writer)
+            }
+        };
+
+        writer.put(key, value)
+    }
+
+    /// Ingest the file into its column family, if anything was written
+    fn ingest<E>(self, db: &DB) -> Result<(), Error<E>> {
+        if let Some(mut writer) = self.writer {
+            writer.finish()?;
+
+            let cf = db.cf_handle(self.cf).ok_or(Error::InvalidState)?;
+            db.ingest_external_file_cf_opts(&cf, &ingest_options(), vec![self.path])?;
+        }
+
+        Ok(())
+    }
+}
+
+fn ingest_options() -> IngestExternalFileOptions {
+    let mut options = IngestExternalFileOptions::default();
+    options.set_move_files(true);
+    options
+}
+
+/// The history entries for the line stored for a key and a colliding line, by staged key
+type HistoryEntries = [(Vec<u8>, Vec<u8>); 2];
 
-        Ok(result)
+/// The main database, which only supports transactions when lines are not staged
+enum Database {
+    Transaction(TransactionDB),
+    /// Staged lines are merged into SST files that are ingested into the database
+    Bulk(DB),
+}
+
+type DbIterator<'a> = Box<dyn Iterator<Item = Result<(Box<[u8]>, Box<[u8]>), rocksdb::Error>> + 'a>;
+
+impl Database {
+    fn cf<E>(&self, name: &str) -> Result<&ColumnFamily, Error<E>> {
+        match self {
+            Self::Transaction(db) => db.cf_handle(name),
+            Self::Bulk(db) => db.cf_handle(name),
+        }
+        .ok_or(Error::InvalidState)
+    }
+
+    fn get_cf<E>(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error<E>> {
+        let cf = self.cf(cf)?;
+
+        Ok(match self {
+            Self::Transaction(db) => db.get_cf(cf, key)?,
+            Self::Bulk(db) => db.get_cf(cf, key)?,
+        })
     }
 
-    pub fn write<P: AsRef<Path>>(
+    fn put_cf<E>(
         &self,
-        base: P,
-        compression: Option<u8>,
-        progress_bar: Option<indicatif::ProgressBar>,
-    ) -> Result<WriteReport, Error<F::Error>> {
-        let mut file_counts = HashMap::new();
-        let mut last_path = None;
-        let mut writer: Option<Box<dyn Write>> = None;
-
-        for result in self.lines() {
-            let (key, value) = result?;
-            let path = F::path(&key).map_err(Error::Format)?;
-
-            let count = if Some(&path) != last_path.as_ref() {
-                let entry = file_counts.entry(path.clone());
-                match entry {
-                    Entry::Occupied(_) => Err(Error::InvalidPath(path.clone(), key.to_vec())),
-                    Entry::Vacant(_) => Ok(()),
-                }?;
-
-                match compression {
-                    Some(level) => {
-                        let extension = path.extension();
-                        let mut new_extension = extension.unwrap_or_default().to_os_string();
-                        if !new_extension.is_empty() {
-                            new_extension.push(".");
-                        }
-                        new_extension.push(ZSTD_EXTENSION);
-
-                        let mut new_path = path.clone();
-                        new_path.set_extension(new_extension);
-
-                        let file = File::create(base.as_ref().join(&new_path))?;
-                        writer = Some(Box::new(
-                            zstd::stream::write::Encoder::new(file, level as i32)?.auto_finish(),
-                        ));
+        cf: &str,
+        key: &[u8],
+        value: &[u8],
+        options: &WriteOptions,
+    ) -> Result<(), Error<E>> {
+        let cf = self.cf(cf)?;
+
+        match self {
+            Self::Transaction(db) => db.put_cf_opt(cf, key, value, options)?,
+            Self::Bulk(db) => db.put_cf_opt(cf, key, value, options)?,
+        }
+
+        Ok(())
+    }
+
+    fn iterator<E>(&self, cf: &str) -> Result<DbIterator<'_>, Error<E>> {
+        let cf = self.cf(cf)?;
+
+        Ok(match self {
+            Self::Transaction(db) => Box::new(db.iterator_cf(cf, IteratorMode::Start)),
+            Self::Bulk(db) => Box::new(db.iterator_cf(cf, IteratorMode::Start)),
+        })
+    }
+}
+
+/// A RocksDB-backed store
+///
+/// The database may be kept in a persistent directory, where it records a manifest of the
+/// inputs that have been fully read, so that an interrupted session can be resumed. It may also
+/// record the input location of each kept line, which remains valid across sessions, and every
+/// line inserted for each key, so that all of the distinct lines for a key can be listed.
+#[derive(Clone)]
+pub struct LineDb<F> {
+    db: Arc<Database>,
+    resolver: Resolver,
+    format: F,
+    staging: Option<Arc<Staging>>,
+    chunks: Chunks,
+    disable_wal: bool,
+    /// The session's sources, if the locations of kept lines are recorded
+    provenance: Option<Arc<Sources>>,
+    /// The session's sources, if the lines of colliding keys are recorded
+    history: Option<Arc<Sources>>,
+}
+
+impl<F: RuntimeFormat> LineDb<F> {
+    /// Open a database, creating it if necessary
+    ///
+    /// An existing database keeps its lines and its manifest of fully read inputs. Lines that
+    /// are staged (see [`Engine`]) are kept under the temporary base path until they are merged.
+    pub fn open<P: AsRef<Path>, T: AsRef<Path>>(
+        format: F,
+        path: P,
+        temp_base: T,
+        engine: Engine,
+        policy: CollisionPolicy,
+        precedence: Precedence,
+        options: &DbOptions,
+    ) -> Result<Self, Error<F::Error>> {
+        // Clones share the block cache, and every column family is listed so that none is opened
+        // with the default options
+        let disable_wal = options.disable_wal;
+        let options = options.to_options();
+        let column_families = [
+            DEFAULT_COLUMN_FAMILY_NAME,
+            MANIFEST_CF,
+            PROVENANCE_CF,
+            HISTORY_CF,
+            SOURCES_CF,
+        ]
+        .map(|name| ColumnFamilyDescriptor::new(name, options.clone()));
+        let staged = engine == Engine::Bulk || precedence == Precedence::Input;
+
+        let db = if staged {
+            Database::Bulk(DB::open_cf_descriptors(&options, path, column_families)?)
+        } else {
+            Database::Transaction(TransactionDB::open_cf_descriptors(
+                &options,
+                &Default::default(),
+                path,
+                column_families,
+            )?)
+        };
+
+        let staging = if staged {
+            Some(Arc::new(Staging::new(temp_base, options)?))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            db: Arc::new(db),
+            resolver: Resolver::new(policy, precedence),
+            format,
+            staging,
+            chunks: Chunks::default(),
+            disable_wal,
+            provenance: None,
+            history: None,
+        })
+    }
+
+    /// Keep every distinct line for each colliding key, given the session's sources
+    pub fn with_history(self, sources: Arc<Sources>) -> Self {
+        Self {
+            history: Some(sources),
+            ..self
+        }
+    }
+
+    /// Record the location of each kept line, given the session's sources
+    pub fn with_provenance(self, sources: Arc<Sources>) -> Self {
+        Self {
+            provenance: Some(sources),
+            ..self
+        }
+    }
+
+    fn stage(
+        &self,
+        staging: &Staging,
+        line: &[u8],
+        position: Position,
+    ) -> Result<(), Error<F::Error>> {
+        let key = crate::store::staged_key(&crate::store::line_key(&self.format, line)?, position);
+
+        if let Some(entries) = self.chunks.push(key, line)? {
+            staging.ingest(&entries)?;
+        }
+
+        Ok(())
+    }
+
+    /// Merge the staged lines into the database in input order, passing each repeat to
+    /// `on_repeat` as it is found
+    ///
+    /// The repeats and stored values are the same as those produced by inserting the lines one at
+    /// a time in input order. The merged values are written to SST files that are ingested into
+    /// the database.
+    fn merge_staged(
+        &self,
+        staging: &Staging,
+        on_repeat: &mut RepeatFn<'_>,
+    ) -> Result<(), Error<F::Error>> {
+        let Database::Bulk(db) = self.db.as_ref() else {
+            return Err(Error::InvalidState);
+        };
+
+        for entries in self.chunks.take_all()? {
+            staging.ingest(&entries)?;
+        }
+
+        let mut staging_db = staging.db.lock().map_err(|_| Error::InvalidState)?;
+        let options = &staging.options;
+        let mut values = SstOutput::new(staging, DEFAULT_COLUMN_FAMILY_NAME, options);
+        let mut locations = SstOutput::new(staging, PROVENANCE_CF, options);
+        let mut history = SstOutput::new(staging, HISTORY_CF, options);
+        // The current key's value, with the location of its kept line if it was staged
+        let mut current: Option<(Vec<u8>, Vec<u8>, Option<Location>)> = None;
+        // The current key's history entries, which are only written in order once it is done
+        let mut key_history = BTreeMap::<Vec<u8>, Vec<u8>>::new();
+
+        for result in staging_db.0.iterator(IteratorMode::Start) {
+            let (staged_key, line) = result?;
+            let (key, position) =
+                crate::store::decode_staged_key(&staged_key).ok_or(Error::InvalidState)?;
+
+            let (value, location) = match current.take() {
+                Some((current_key, value, location)) if current_key == key => {
+                    (Some(value), location)
+                }
+                Some((current_key, value, location)) => {
+                    values.put(&current_key, &value)?;
+
+                    if let Some(location) = location {
+                        locations.put(&current_key, &encode_location(&location))?;
                     }
-                    None => {
-                        let file = File::create(base.as_ref().join(&path))?;
-                        writer = Some(Box::new(BufWriter::new(file)));
+
+                    for (history_key, entry) in std::mem::take(&mut key_history) {
+                        history.put(&history_key, &entry)?;
                     }
+
+                    (db.get(&key)?, None)
                 }
+                None => (db.get(&key)?, None),
+            };
 
-                last_path = Some(path);
-                entry
-            } else {
-                let entry = file_counts.entry(path.clone());
-                match entry {
-                    Entry::Occupied(_) => Ok(()),
-                    Entry::Vacant(_) => Err(Error::InvalidState),
-                }?;
-                entry
-            }
-            .or_default();
+            let (updated, repeat) = self.resolver.combine(value.as_deref(), &line, position)?;
 
-            match writer {
-                Some(ref mut writer) => {
-                    *count += 1;
-                    Ok(writeln!(writer, "{}", value)?)
+            if let Some([(stored_key, stored_entry), (line_key, entry)]) =
+                self.history_entries(&key, value.as_deref(), &line, position, repeat.as_ref())?
+            {
+                if !key_history.contains_key(&stored_key)
+                    && self.db.get_cf(HISTORY_CF, &stored_key)?.is_none()
+                {
+                    key_history.insert(stored_key, stored_entry);
                 }
-                None => Err(Error::InvalidState),
-            }?;
 
-            if let Some(progress_bar) = progress_bar.as_ref() {
-                progress_bar.inc(1);
+                key_history.insert(line_key, entry);
+            }
+
+            if let Some(repeat) = repeat {
+                on_repeat(repeat)?;
             }
+
+            let location = match updated.as_deref() {
+                Some(updated) => self.location(updated, position)?.or(location),
+                None => location,
+            };
+
+            if let Some(value) = updated.or(value) {
+                current = Some((key, value, location));
+            }
+        }
+
+        if let Some((key, value, location)) = current {
+            values.put(&key, &value)?;
+
+            if let Some(location) = location {
+                locations.put(&key, &encode_location(&location))?;
+            }
+
+            for (history_key, entry) in key_history {
+                history.put(&history_key, &entry)?;
+            }
+        }
+
+        values.ingest(db)?;
+        locations.ingest(db)?;
+        history.ingest(db)?;
+
+        // Start a new staging database so that lines are not merged again
+        let generation = staging_db.1 + 1;
+        let previous_path = staging
+            .dir
+            .path()
+            .join(format!("generation-{}", staging_db.1));
+        *staging_db = (
+            Staging::open_generation(&staging.options, staging.dir.path(), generation)?,
+            generation,
+        );
+        std::fs::remove_dir_all(previous_path)?;
+
+        let completed =
+            std::mem::take(&mut *staging.completed.lock().map_err(|_| Error::InvalidState)?);
+
+        for (source, entry) in completed {
+            self.record_complete(&source, &entry)?;
         }
 
-        Ok(WriteReport::new(file_counts))
+        Ok(())
     }
 
-    fn lines(&self) -> impl Iterator<Item = Result<(Box<[u8]>, String), Error<F::Error>>> + '_ {
-        self.db.iterator(IteratorMode::Start).map(|result| {
+    /// The location of the line at a position, if locations are recorded and it is the kept line
+    /// of a stored value
+    ///
+    /// The kept line may otherwise have been inserted by an earlier session, whose sources are
+    /// not known.
+    fn location(
+        &self,
+        value: &[u8],
+        position: Position,
+    ) -> Result<Option<Location>, Error<F::Error>> {
+        let Some(sources) = self.provenance.as_ref() else {
+            return Ok(None);
+        };
+
+        if StoredValue::decode(value).ok_or(Error::InvalidState)?.kept != position {
+            return Ok(None);
+        }
+
+        Ok(Some(sources.location(position).ok_or(Error::InvalidState)?))
+    }
+
+    /// The history entry for a line, if the lines of colliding keys are recorded
+    fn history_entry(
+        &self,
+        line: &[u8],
+        position: Position,
+    ) -> Result<Option<Vec<u8>>, Error<F::Error>> {
+        let Some(sources) = self.history.as_ref() else {
+            return Ok(None);
+        };
+
+        let location = sources.location(position).ok_or(Error::InvalidState)?;
+
+        Ok(Some(crate::key::encode(&(
+            line,
+            encode_location(&location).as_slice(),
+        ))))
+    }
+
+    /// The history entries for the line stored for a key and for a colliding line, by staged key
+    ///
+    /// A key's history starts at its first collision, with the line that was stored for it. Its
+    /// entry must not replace one that was already recorded, as the value may have been merged.
+    fn history_entries(
+        &self,
+        key: &[u8],
+        value: Option<&[u8]>,
+        line: &[u8],
+        position: Position,
+        repeat: Option<&KeyRepeat>,
+    ) -> Result<Option<HistoryEntries>, Error<F::Error>> {
+        let (
+            Some(value),
+            Some(KeyRepeat {
+                replacement: Some(_),
+                ..
+            }),
+        ) = (value, repeat)
+        else {
+            return Ok(None);
+        };
+
+        let stored = StoredValue::decode(value).ok_or(Error::InvalidState)?;
+
+        let (Some(stored_entry), Some(entry)) = (
+            self.history_entry(stored.line, stored.kept)?,
+            self.history_entry(line, position)?,
+        ) else {
+            return Ok(None);
+        };
+
+        Ok(Some([
+            (crate::store::staged_key(key, stored.kept), stored_entry),
+            (crate::store::staged_key(key, position), entry),
+        ]))
+    }
+
+    fn write_options(&self) -> WriteOptions {
+        let mut options = WriteOptions::default();
+        options.disable_wal(self.disable_wal);
+        options
+    }
+
+    fn record_complete(
+        &self,
+        source: &Source,
+        entry: &ManifestEntry,
+    ) -> Result<(), Error<F::Error>> {
+        // Without the write-ahead log, the entry is written after the input's lines in the same
+        // way, and the atomic flush of every column family keeps it from outliving them
+        self.db.put_cf(
+            MANIFEST_CF,
+            &ManifestEntry::manifest_key(source),
+            &entry.encode(),
+            &self.write_options(),
+        )
+    }
+}
+
+impl<F: RuntimeFormat> LineStore<F> for LineDb<F> {
+    fn format(&self) -> &F {
+        &self.format
+    }
+
+    fn insert(
+        &self,
+        line: &[u8],
+        position: Position,
+    ) -> Result<Option<KeyRepeat>, Error<F::Error>> {
+        if let Some(staging) = self.staging.as_ref() {
+            self.stage(staging, line, position)?;
+
+            return Ok(None);
+        }
+
+        let Database::Transaction(db) = self.db.as_ref() else {
+            return Err(Error::InvalidState);
+        };
+
+        let key = crate::store::line_key(&self.format, line)?;
+        let tx = db.transaction_opt(&self.write_options(), &TransactionOptions::default());
+        let value = tx.get_for_update(&key, true)?;
+        let (updated, repeat) = self.resolver.combine(value.as_deref(), line, position)?;
+
+        if let Some([(stored_key, stored_entry), (line_key, entry)]) =
+            self.history_entries(&key, value.as_deref(), line, position, repeat.as_ref())?
+        {
+            let cf = self.db.cf(HISTORY_CF)?;
+
+            if tx.get_cf(cf, &stored_key)?.is_none() {
+                tx.put_cf(cf, stored_key, stored_entry)?;
+            }
+
+            tx.put_cf(cf, line_key, entry)?;
+        }
+
+        if let Some(updated) = updated {
+            if let Some(location) = self.location(&updated, position)? {
+                let cf = self.db.cf(PROVENANCE_CF)?;
+                tx.put_cf(cf, &key, encode_location(&location))?;
+            }
+
+            tx.put(&key, updated)?;
+        }
+        tx.commit()?;
+
+        Ok(repeat)
+    }
+
+    fn flush(&self) -> Result<(), Error<F::Error>> {
+        if let Some(staging) = self.staging.as_ref() {
+            let entries = self.chunks.take_local()?;
+
+            if !entries.is_empty() {
+                staging.ingest(&entries)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(&self, on_repeat: &mut RepeatFn<'_>) -> Result<(), Error<F::Error>> {
+        match self.staging.as_ref() {
+            Some(staging) => self.merge_staged(staging, on_repeat),
+            None => Ok(()),
+        }
+    }
+
+    fn count(&self) -> usize {
+        self.db
+            .iterator::<F::Error>(DEFAULT_COLUMN_FAMILY_NAME)
+            .map_or(0, Iterator::count)
+    }
+
+    fn lines(&self) -> Box<dyn Iterator<Item = LineResult<F::Error>> + '_> {
+        let iterator = match self.db.iterator(DEFAULT_COLUMN_FAMILY_NAME) {
+            Ok(iterator) => iterator,
+            Err(error) => return Box::new(std::iter::once(Err(error))),
+        };
+
+        Box::new(iterator.map(|result| {
             let (key, value) = result?;
-            let value_string = std::str::from_utf8(&value)?.to_string();
-            Ok((key, value_string))
-        })
+            let stored = StoredValue::decode(&value).ok_or(Error::InvalidState)?;
+            Ok((key, stored.line.to_vec(), stored.kept))
+        }))
+    }
+
+    fn manifest_entry(&self, source: &Source) -> Result<Option<ManifestEntry>, Error<F::Error>> {
+        self.db
+            .get_cf(MANIFEST_CF, &ManifestEntry::manifest_key(source))?
+            .map(|bytes| ManifestEntry::decode(&bytes).ok_or(Error::InvalidState))
+            .transpose()
+    }
+
+    fn record_source(&self, file_index: usize, source: &Source) -> Result<(), Error<F::Error>> {
+        self.db.put_cf(
+            SOURCES_CF,
+            &(file_index as u64).to_be_bytes(),
+            &crate::key::encode(&(
+                source.path.to_string_lossy().as_ref(),
+                source.member.as_deref(),
+            )),
+            &self.write_options(),
+        )
     }
+
+    fn recorded_sources(&self) -> Result<Vec<(usize, Source)>, Error<F::Error>> {
+        self.db
+            .iterator(SOURCES_CF)?
+            .map(|result| {
+                let (key, value) = result?;
+                let file_index =
+                    u64::from_be_bytes((*key).try_into().map_err(|_| Error::InvalidState)?);
+                let (path, member) = crate::key::decode::<(String, Option<String>)>(&value)
+                    .map_err(|_| Error::InvalidState)?;
+
+                Ok((
+                    file_index as usize,
+                    Source {
+                        path: path.into(),
+                        member,
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    fn kept_location(&self, key: &[u8]) -> Result<Option<Location>, Error<F::Error>> {
+        self.db
+            .get_cf(PROVENANCE_CF, key)?
+            .map(|bytes| decode_location(&bytes).ok_or(Error::InvalidState))
+            .transpose()
+    }
+
+    fn collisions<'a>(&'a self) -> Box<dyn Iterator<Item = CollisionResult<F::Error>> + 'a>
+    where
+        F::Error: 'a,
+    {
+        let iterator = match self.db.iterator(HISTORY_CF) {
+            Ok(iterator) => iterator,
+            Err(error) => return Box::new(std::iter::once(Err(error))),
+        };
+
+        crate::store::group_collisions(iterator.map(|result| {
+            let (staged_key, entry) = result?;
+            let (key, _) =
+                crate::store::decode_staged_key(&staged_key).ok_or(Error::InvalidState)?;
+            let (line, location) = crate::key::decode::<(Vec<u8>, Vec<u8>)>(&entry)
+                .ok()
+                .and_then(|(line, location)| Some((line, decode_location(&location)?)))
+                .ok_or(Error::InvalidState)?;
+
+            Ok((key, line, location))
+        }))
+    }
+
+    /// Staged inputs are only recorded once their lines have been merged
+    fn complete(&self, source: &Source, entry: &ManifestEntry) -> Result<(), Error<F::Error>> {
+        match self.staging.as_ref() {
+            Some(staging) => {
+                staging
+                    .completed
+                    .lock()
+                    .map_err(|_| Error::InvalidState)?
+                    .push((source.clone(), *entry));
+
+                Ok(())
+            }
+            None => self.record_complete(source, entry),
+        }
+    }
+}
+
+/// Paths are recorded as (possibly lossily converted) strings
+fn encode_location(location: &Location) -> Vec<u8> {
+    crate::key::encode(&(
+        location.path.to_string_lossy().as_ref(),
+        location.member.as_deref(),
+        location.line_number as u64,
+        location.byte_offset,
+    ))
+}
+
+fn decode_location(bytes: &[u8]) -> Option<Location> {
+    let (path, member, line_number, byte_offset) =
+        crate::key::decode::<(String, Option<String>, u64, u64)>(bytes).ok()?;
+
+    Some(Location {
+        path: path.into(),
+        member,
+        line_number: line_number as usize,
+        byte_offset,
+    })
 }
@@ -0,0 +1,12 @@
+//! Built-in formats
+
+mod component;
+pub mod csv;
+pub mod json;
+pub mod registry;
+mod template;
+
+pub use csv::CsvFormat;
+pub use json::JsonPointerFormat;
+pub use registry::{DynFormat, FormatRegistry};
+pub use template::PathTemplate;
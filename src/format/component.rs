@@ -0,0 +1,110 @@
+use crate::key::{self, Decode, Encode};
+use std::fmt::Display;
+
+const TAG_NULL: u8 = 0x01;
+const TAG_FALSE: u8 = 0x02;
+const TAG_TRUE: u8 = 0x03;
+const TAG_NUMBER: u8 = 0x04;
+const TAG_STRING: u8 = 0x05;
+
+const NUMBER_FLOAT: u8 = 0x00;
+const NUMBER_INT: u8 = 0x01;
+
+/// A typed key component with an order-preserving encoding
+///
+/// Values of the same type sort in their natural order, and types sort in declaration order
+/// (integers and floats are both numbers, and sort together).
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum Component {
+    Null,
+    Bool(bool),
+    Int(i128),
+    /// A number that is not an integer in the range of `i128`
+    Float(f64),
+    String(String),
+}
+
+impl Component {
+    /// A number, which is an integer if it has no fractional part (so that `2.0` equals `2`)
+    pub(super) fn float(value: f64) -> Self {
+        if value.fract() == 0.0 && value >= i128::MIN as f64 && value < i128::MAX as f64 {
+            Self::Int(value as i128)
+        } else {
+            Self::Float(value)
+        }
+    }
+
+    /// Numbers are encoded as their nearest `f64` followed by the exact value
+    ///
+    /// A float that is not stored as an integer is either smaller in magnitude than 2^52 (where
+    /// integers are exact `f64` values) or outside the range of `i128`, so it never has the same
+    /// nearest `f64` as an integer, and the exact value only orders integers.
+    pub(super) fn encode(&self, acc: &mut Vec<u8>) {
+        match self {
+            Self::Null => acc.push(TAG_NULL),
+            Self::Bool(false) => acc.push(TAG_FALSE),
+            Self::Bool(true) => acc.push(TAG_TRUE),
+            Self::Int(value) => {
+                acc.push(TAG_NUMBER);
+                (*value as f64).encode_to(acc);
+                acc.push(NUMBER_INT);
+                value.encode_to(acc);
+            }
+            Self::Float(value) => {
+                acc.push(TAG_NUMBER);
+                value.encode_to(acc);
+                acc.push(NUMBER_FLOAT);
+            }
+            Self::String(value) => {
+                acc.push(TAG_STRING);
+                value.encode_to(acc);
+            }
+        }
+    }
+
+    pub(super) fn decode_all(mut key: &[u8]) -> Result<Vec<Component>, key::Error> {
+        let mut components = vec![];
+
+        while let Some((tag, rest)) = key.split_first() {
+            let (component, rest) = match *tag {
+                TAG_NULL => (Component::Null, rest),
+                TAG_FALSE => (Component::Bool(false), rest),
+                TAG_TRUE => (Component::Bool(true), rest),
+                TAG_NUMBER => {
+                    let (approximate, rest) = f64::decode_from(rest)?;
+
+                    match rest.split_first() {
+                        Some((&NUMBER_FLOAT, rest)) => (Component::Float(approximate), rest),
+                        Some((&NUMBER_INT, rest)) => {
+                            let (value, rest) = i128::decode_from(rest)?;
+                            (Component::Int(value), rest)
+                        }
+                        _ => return Err(key::Error::InvalidComponent),
+                    }
+                }
+                TAG_STRING => {
+                    let (value, rest) = String::decode_from(rest)?;
+                    (Component::String(value), rest)
+                }
+                _ => return Err(key::Error::InvalidComponent),
+            };
+
+            components.push(component);
+            key = rest;
+        }
+
+        Ok(components)
+    }
+}
+
+impl Display for Component {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Null => write!(f, "null"),
+            Self::Bool(value) => write!(f, "{}", value),
+            Self::Int(value) => write!(f, "{}", value),
+            Self::Float(value) => write!(f, "{}", value),
+            Self::String(value) => write!(f, "{}", value),
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use super::{component::Component, template::PathTemplate};
+use crate::{RuntimeFormat, lines::RecordDelimiter};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+const QUOTE: char = '"';
+
+#[derive(thiserror::Error, Debug, Eq, PartialEq)]
+pub enum Error {
+    #[error("Unterminated quoted field")]
+    UnterminatedQuote(String),
+    #[error("Unexpected character after quoted field")]
+    InvalidQuote(String),
+    #[error("No key columns")]
+    MissingColumns,
+    #[error("Missing column")]
+    MissingColumn(Column),
+    #[error("Missing header")]
+    MissingHeader,
+    #[error("Header does not match first header")]
+    HeaderMismatch { expected: String, found: String },
+    #[error("Column names require a header")]
+    NamedColumnWithoutHeader(String),
+    #[error("Invalid key")]
+    InvalidKey(#[from] crate::key::Error),
+    #[error("Path template error")]
+    Template(#[from] super::template::Error),
+}
+
+/// A column selected by header name or zero-based index
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Column {
+    Name(String),
+    Index(usize),
+}
+
+impl From<&str> for Column {
+    fn from(value: &str) -> Self {
+        Self::Name(value.to_string())
+    }
+}
+
+impl From<usize> for Column {
+    fn from(value: usize) -> Self {
+        Self::Index(value)
+    }
+}
+
+#[derive(Debug)]
+struct Header {
+    line: String,
+    indices: Vec<usize>,
+}
+
+/// A delimited format (CSV or TSV) that builds keys from one or more columns
+///
+/// Fields may be quoted, with doubled quotes as escapes, and quoted fields may contain line
+/// breaks. When the format has a header, the header line of every input file must match the first
+/// one read, and it is written at the top of every output file.
+#[derive(Clone, Debug)]
+pub struct CsvFormat {
+    columns: Vec<Column>,
+    template: PathTemplate,
+    delimiter: char,
+    has_header: bool,
+    header: Arc<OnceLock<Header>>,
+    recursive: bool,
+}
+
+impl CsvFormat {
+    pub fn new<C: Into<Column>, I: IntoIterator<Item = C>>(
+        columns: I,
+        template: &str,
+    ) -> Result<Self, Error> {
+        let columns = columns.into_iter().map(Into::into).collect::<Vec<_>>();
+
+        if columns.is_empty() {
+            return Err(Error::MissingColumns);
+        }
+
+        let template = template.parse::<PathTemplate>()?;
+
+        if template.component_count() > columns.len() {
+            return Err(
+                super::template::Error::MissingComponent(template.component_count() - 1).into(),
+            );
+        }
+
+        Ok(Self {
+            columns,
+            template,
+            delimiter: ',',
+            has_header: true,
+            header: Arc::default(),
+            recursive: false,
+        })
+    }
+
+    pub fn with_delimiter(self, delimiter: char) -> Self {
+        Self { delimiter, ..self }
+    }
+
+    /// Indicate whether the first line of each input file is a header (the default)
+    pub fn with_header(self, has_header: bool) -> Result<Self, Error> {
+        if let Some(Column::Name(name)) = self
+            .columns
+            .iter()
+            .find(|column| !has_header && matches!(column, Column::Name(_)))
+        {
+            return Err(Error::NamedColumnWithoutHeader(name.clone()));
+        }
+
+        Ok(Self { has_header, ..self })
+    }
+
+    pub fn with_recursive(self, recursive: bool) -> Self {
+        Self { recursive, ..self }
+    }
+
+    fn column_indices(&self, header_fields: Option<&[String]>) -> Result<Vec<usize>, Error> {
+        self.columns
+            .iter()
+            .map(|column| match column {
+                Column::Index(index) => Ok(*index),
+                Column::Name(name) => header_fields
+                    .and_then(|fields| fields.iter().position(|field| field == name))
+                    .ok_or_else(|| Error::MissingColumn(column.clone())),
+            })
+            .collect()
+    }
+}
+
+impl RuntimeFormat for CsvFormat {
+    type Error = Error;
+
+    fn key(&self, line: &str) -> Result<Vec<u8>, Self::Error> {
+        let unresolved;
+        let indices = if self.has_header {
+            &self.header.get().ok_or(Error::MissingHeader)?.indices
+        } else {
+            unresolved = self.column_indices(None)?;
+            &unresolved
+        };
+
+        let fields = parse_fields(line, self.delimiter)?;
+        let mut key = vec![];
+
+        for index in indices {
+            let field = fields
+                .get(*index)
+                .ok_or(Error::MissingColumn(Column::Index(*index)))?;
+
+            Component::String(field.clone()).encode(&mut key);
+        }
+
+        Ok(key)
+    }
+
+    fn path(&self, key: &[u8]) -> Result<PathBuf, Self::Error> {
+        let components = Component::decode_all(key)?;
+
+        Ok(self.template.render(&components)?)
+    }
+
+    fn is_input_recursive(&self) -> bool {
+        self.recursive
+    }
+
+    fn record_delimiter(&self) -> RecordDelimiter {
+        RecordDelimiter::Csv {
+            field_delimiter: self.delimiter,
+        }
+    }
+
+    fn has_header(&self) -> bool {
+        self.has_header
+    }
+
+    fn read_header(&self, line: &str) -> Result<(), Self::Error> {
+        let header = match self.header.get() {
+            Some(header) => header,
+            None => {
+                let fields = parse_fields(line, self.delimiter)?;
+                let indices = self.column_indices(Some(&fields))?;
+
+                self.header.get_or_init(|| Header {
+                    line: line.to_string(),
+                    indices,
+                })
+            }
+        };
+
+        if header.line == line {
+            Ok(())
+        } else {
+            Err(Error::HeaderMismatch {
+                expected: header.line.clone(),
+                found: line.to_string(),
+            })
+        }
+    }
+
+    fn header(&self, _path: &Path) -> Option<String> {
+        self.header.get().map(|header| header.line.clone())
+    }
+}
+
+fn parse_fields(line: &str, delimiter: char) -> Result<Vec<String>, Error> {
+    let mut fields = vec![];
+    let mut chars = line.chars().peekable();
+
+    loop {
+        let mut field = String::new();
+
+        if chars.peek() == Some(&QUOTE) {
+            chars.next();
+
+            loop {
+                match chars.next() {
+                    Some(QUOTE) if chars.peek() == Some(&QUOTE) => {
+                        chars.next();
+                        field.push(QUOTE);
+                    }
+                    Some(QUOTE) => break,
+                    Some(ch) => field.push(ch),
+                    None => return Err(Error::UnterminatedQuote(line.to_string())),
+                }
+            }
+
+            match chars.next() {
+                Some(ch) if ch == delimiter => fields.push(field),
+                None => {
+                    fields.push(field);
+                    break;
+                }
+                Some(_) => return Err(Error::InvalidQuote(line.to_string())),
+            }
+        } else {
+            loop {
+                match chars.next() {
+                    Some(ch) if ch == delimiter => {
+                        fields.push(field);
+                        break;
+                    }
+                    Some(ch) => field.push(ch),
+                    None => {
+                        fields.push(field);
+                        return Ok(fields);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(fields)
+}
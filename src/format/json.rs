@@ -0,0 +1,124 @@
+use super::{component::Component, template::PathTemplate};
+use crate::RuntimeFormat;
+use serde_json::Value;
+use std::path::PathBuf;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("JSON parsing error")]
+    Json(#[from] serde_json::Error),
+    #[error("No JSON pointers")]
+    MissingPointers,
+    #[error("Invalid JSON pointer")]
+    InvalidPointer(String),
+    #[error("Missing value for JSON pointer")]
+    MissingValue(String),
+    #[error("Unsupported value type for JSON pointer")]
+    UnsupportedValue(String),
+    #[error("Invalid key")]
+    InvalidKey(#[from] crate::key::Error),
+    #[error("Path template error")]
+    Template(#[from] super::template::Error),
+}
+
+/// A JSON Lines format that builds keys from the values at one or more JSON pointers
+///
+/// Key components are encoded so that bytewise order matches the natural order of values of the
+/// same type (null, then booleans, numbers, and strings, in that order across types). Integers and
+/// floats are compared as numbers, and a float with no fractional part is the same key as the
+/// equal integer.
+/// Objects and arrays cannot be used as key components.
+#[derive(Clone, Debug)]
+pub struct JsonPointerFormat {
+    pointers: Vec<String>,
+    template: PathTemplate,
+    recursive: bool,
+}
+
+impl JsonPointerFormat {
+    pub fn new<P: Into<String>, I: IntoIterator<Item = P>>(
+        pointers: I,
+        template: &str,
+    ) -> Result<Self, Error> {
+        let pointers = pointers.into_iter().map(Into::into).collect::<Vec<_>>();
+
+        if pointers.is_empty() {
+            return Err(Error::MissingPointers);
+        }
+
+        if let Some(pointer) = pointers
+            .iter()
+            .find(|pointer| !pointer.is_empty() && !pointer.starts_with('/'))
+        {
+            return Err(Error::InvalidPointer(pointer.clone()));
+        }
+
+        let template = template.parse::<PathTemplate>()?;
+
+        if template.component_count() > pointers.len() {
+            return Err(
+                super::template::Error::MissingComponent(template.component_count() - 1).into(),
+            );
+        }
+
+        Ok(Self {
+            pointers,
+            template,
+            recursive: false,
+        })
+    }
+
+    pub fn with_recursive(self, recursive: bool) -> Self {
+        Self { recursive, ..self }
+    }
+
+    pub fn pointers(&self) -> &[String] {
+        &self.pointers
+    }
+}
+
+impl RuntimeFormat for JsonPointerFormat {
+    type Error = Error;
+
+    fn key(&self, line: &str) -> Result<Vec<u8>, Self::Error> {
+        let value = serde_json::from_str::<Value>(line)?;
+        let mut key = vec![];
+
+        for pointer in &self.pointers {
+            let component = value
+                .pointer(pointer)
+                .ok_or_else(|| Error::MissingValue(pointer.clone()))?;
+
+            json_component(component)
+                .ok_or_else(|| Error::UnsupportedValue(pointer.clone()))?
+                .encode(&mut key);
+        }
+
+        Ok(key)
+    }
+
+    fn path(&self, key: &[u8]) -> Result<PathBuf, Self::Error> {
+        let components = Component::decode_all(key)?;
+
+        Ok(self.template.render(&components)?)
+    }
+
+    fn is_input_recursive(&self) -> bool {
+        self.recursive
+    }
+}
+
+fn json_component(value: &Value) -> Option<Component> {
+    match value {
+        Value::Null => Some(Component::Null),
+        Value::Bool(value) => Some(Component::Bool(*value)),
+        Value::Number(number) => number
+            .as_i64()
+            .map(i128::from)
+            .or_else(|| number.as_u64().map(i128::from))
+            .map(Component::Int)
+            .or_else(|| number.as_f64().map(Component::float)),
+        Value::String(value) => Some(Component::String(value.clone())),
+        Value::Array(_) | Value::Object(_) => None,
+    }
+}
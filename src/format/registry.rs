@@ -0,0 +1,148 @@
+//! Named formats chosen at runtime
+
+use crate::{Format, KeyError, RuntimeFormat, Static, lines::RecordDelimiter};
+use clap::ArgMatches;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A format whose error type has been erased, so that formats can be chosen at runtime
+pub type DynFormat = Arc<dyn RuntimeFormat<Error = BoxError> + Send + Sync>;
+
+type Constructor = Box<dyn Fn(&ArgMatches) -> Result<DynFormat, BoxError>>;
+
+/// Erase the error type of a format
+pub fn erase<F: RuntimeFormat + Send + Sync + 'static>(format: F) -> DynFormat
+where
+    F::Error: std::error::Error + Send + Sync + 'static,
+{
+    Arc::new(Erased(format))
+}
+
+/// Adapts a format to the boxed error type
+struct Erased<F>(F);
+
+impl<F: RuntimeFormat> RuntimeFormat for Erased<F>
+where
+    F::Error: std::error::Error + Send + Sync + 'static,
+{
+    type Error = BoxError;
+
+    fn key(&self, line: &str) -> Result<Vec<u8>, Self::Error> {
+        Ok(self.0.key(line)?)
+    }
+
+    fn path(&self, key: &[u8]) -> Result<PathBuf, Self::Error> {
+        Ok(self.0.path(key)?)
+    }
+
+    fn key_bytes(&self, line: &[u8]) -> Result<Vec<u8>, KeyError<Self::Error>> {
+        self.0.key_bytes(line).map_err(|error| match error {
+            KeyError::Utf8(error) => KeyError::Utf8(error),
+            KeyError::Format(error) => KeyError::Format(error.into()),
+        })
+    }
+
+    fn is_input_recursive(&self) -> bool {
+        self.0.is_input_recursive()
+    }
+
+    fn include(&self, path: &Path) -> bool {
+        self.0.include(path)
+    }
+
+    fn record_delimiter(&self) -> RecordDelimiter {
+        self.0.record_delimiter()
+    }
+
+    fn has_header(&self) -> bool {
+        self.0.has_header()
+    }
+
+    fn read_header(&self, line: &str) -> Result<(), Self::Error> {
+        Ok(self.0.read_header(line)?)
+    }
+
+    fn header(&self, path: &Path) -> Option<String> {
+        self.0.header(path)
+    }
+}
+
+struct Registered {
+    name: String,
+    description: String,
+    constructor: Constructor,
+}
+
+/// A set of named formats that an application can choose between at runtime
+///
+/// Formats are listed in the order in which they were registered, and registering a name again
+/// replaces the earlier format.
+#[derive(Default)]
+pub struct FormatRegistry {
+    formats: Vec<Registered>,
+}
+
+impl FormatRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a static format
+    pub fn with_format<F: Format + 'static>(self, name: &str, description: &str) -> Self
+    where
+        F::Error: std::error::Error + Send + Sync + 'static,
+    {
+        self.with_runtime_format(name, description, |_| Ok(Static::<F>::new()))
+    }
+
+    /// Register a format built from the parsed arguments
+    pub fn with_runtime_format<
+        F: RuntimeFormat + Send + Sync + 'static,
+        M: Fn(&ArgMatches) -> Result<F, F::Error> + 'static,
+    >(
+        mut self,
+        name: &str,
+        description: &str,
+        make_format: M,
+    ) -> Self
+    where
+        F::Error: std::error::Error + Send + Sync + 'static,
+    {
+        self.formats.retain(|format| format.name != name);
+        self.formats.push(Registered {
+            name: name.to_string(),
+            description: description.to_string(),
+            constructor: Box::new(move |matches| Ok(erase(make_format(matches)?))),
+        });
+
+        self
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.formats
+            .iter()
+            .map(|format| format.name.as_str())
+            .collect()
+    }
+
+    /// The name and description of each format
+    pub fn descriptions(&self) -> Vec<(&str, &str)> {
+        self.formats
+            .iter()
+            .map(|format| (format.name.as_str(), format.description.as_str()))
+            .collect()
+    }
+
+    /// Build the format with the given name from the parsed arguments
+    pub fn make(&self, name: &str, matches: &ArgMatches) -> Result<DynFormat, BoxError> {
+        let format = self
+            .formats
+            .iter()
+            .find(|format| format.name == name)
+            .ok_or_else(|| format!("Unknown format: {name}"))?;
+
+        (format.constructor)(matches)
+    }
+}
@@ -0,0 +1,141 @@
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+#[derive(thiserror::Error, Debug, Eq, PartialEq)]
+pub enum Error {
+    #[error("Invalid path template")]
+    Invalid(String),
+    #[error("Missing key component")]
+    MissingComponent(usize),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Segment {
+    Literal(String),
+    Component {
+        index: usize,
+        max_len: Option<usize>,
+    },
+}
+
+/// An output path built from the components of a key
+///
+/// Placeholders have the form `{N}` (the Nth key component) or `{N:L}` (at most the first `L`
+/// characters of the Nth component), and `{{` and `}}` are literal braces. Path separators in
+/// component values are replaced with `_`, so every key maps to a file directly in the output
+/// directory unless the template itself contains separators. Empty, `.` and `..` path components
+/// in the rendered path have each character (or the empty string) replaced with `_`, so that
+/// every path stays inside the output directory.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PathTemplate {
+    segments: Vec<Segment>,
+}
+
+impl PathTemplate {
+    /// The number of key components referenced by the template
+    pub fn component_count(&self) -> usize {
+        self.segments
+            .iter()
+            .filter_map(|segment| match segment {
+                Segment::Component { index, .. } => Some(index + 1),
+                Segment::Literal(_) => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn render<C: Display>(&self, components: &[C]) -> Result<PathBuf, Error> {
+        let mut result = String::new();
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(value) => result.push_str(value),
+                Segment::Component { index, max_len } => {
+                    let value = components
+                        .get(*index)
+                        .ok_or(Error::MissingComponent(*index))?
+                        .to_string();
+                    let value = value.chars().take(max_len.unwrap_or(usize::MAX));
+
+                    result
+                        .extend(value.map(|ch| if std::path::is_separator(ch) { '_' } else { ch }));
+                }
+            }
+        }
+
+        Ok(result
+            .split(std::path::is_separator)
+            .map(|component| match component {
+                "" | "." => "_",
+                ".." => "__",
+                other => other,
+            })
+            .collect())
+    }
+}
+
+impl FromStr for PathTemplate {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut segments = vec![];
+        let mut literal = String::new();
+        let mut chars = value.chars().peekable();
+
+        while let Some(ch) = chars.next() {
+            match ch {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut placeholder = String::new();
+                    let mut closed = false;
+
+                    for ch in chars.by_ref() {
+                        if ch == '}' {
+                            closed = true;
+                            break;
+                        }
+                        placeholder.push(ch);
+                    }
+
+                    if !closed {
+                        return Err(Error::Invalid(value.to_string()));
+                    }
+
+                    let (index, max_len) = match placeholder.split_once(':') {
+                        Some((index, max_len)) => (index, Some(max_len)),
+                        None => (placeholder.as_str(), None),
+                    };
+
+                    let index = index
+                        .parse::<usize>()
+                        .map_err(|_| Error::Invalid(value.to_string()))?;
+                    let max_len = max_len
+                        .map(|max_len| max_len.parse::<usize>())
+                        .transpose()
+                        .map_err(|_| Error::Invalid(value.to_string()))?;
+
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Component { index, max_len });
+                }
+                '}' => return Err(Error::Invalid(value.to_string())),
+                other => literal.push(other),
+            }
+        }
+
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(Self { segments })
+    }
+}
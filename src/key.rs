@@ -0,0 +1,527 @@
+//! Order-preserving key encodings
+//!
+//! Values are encoded so that the bytewise order of encodings (as used by RocksDB) matches the
+//! natural order of the values. Fixed-width values are encoded in big-endian form (with the sign
+//! bit flipped for signed integers and floats), and variable-width values are escaped and
+//! terminated, so tuples of components can be concatenated and decoded back unambiguously.
+//! Wrapping a component in [`Desc`] reverses its order within a composite key.
+//!
+//! ```
+//! use rearranger::key::{self, Desc};
+//!
+//! let a = key::encode(&("alice", Desc(10_i64)));
+//! let b = key::encode(&("alice", Desc(-3_i64)));
+//! assert!(a < b);
+//!
+//! let (name, Desc(value)): (String, Desc<i64>) = key::decode(&a).unwrap();
+//! assert_eq!((name.as_str(), value), ("alice", 10));
+//! ```
+
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+
+const ESCAPE: u8 = 0x00;
+const ESCAPED_NUL: u8 = 0xff;
+const END: u8 = 0x00;
+
+const NONE: u8 = 0x00;
+const SOME: u8 = 0x01;
+
+#[derive(thiserror::Error, Debug, Eq, PartialEq)]
+pub enum Error {
+    #[error("Unexpected end of key")]
+    UnexpectedEnd,
+    #[error("Invalid key component")]
+    InvalidComponent,
+    #[error("Invalid UTF-8 in key component")]
+    Utf8(#[from] std::string::FromUtf8Error),
+    #[error("Unexpected trailing bytes in key")]
+    TrailingBytes(usize),
+}
+
+/// A value with an order-preserving encoding
+pub trait Encode {
+    fn encode_to(&self, acc: &mut Vec<u8>);
+}
+
+/// A value that can be decoded from the start of an encoded key
+pub trait Decode: Sized {
+    /// Decode a value, returning it together with the remaining bytes
+    fn decode_from(bytes: &[u8]) -> Result<(Self, &[u8]), Error>;
+}
+
+/// Encode a value (often a tuple of components) as a key
+pub fn encode<E: Encode + ?Sized>(value: &E) -> Vec<u8> {
+    let mut acc = vec![];
+    value.encode_to(&mut acc);
+    acc
+}
+
+/// Decode a complete key, failing if any bytes are left over
+pub fn decode<D: Decode>(bytes: &[u8]) -> Result<D, Error> {
+    let (value, rest) = D::decode_from(bytes)?;
+
+    if rest.is_empty() {
+        Ok(value)
+    } else {
+        Err(Error::TrailingBytes(rest.len()))
+    }
+}
+
+/// A component that sorts in descending order
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Desc<T>(pub T);
+
+impl<T: Encode> Encode for Desc<T> {
+    fn encode_to(&self, acc: &mut Vec<u8>) {
+        let start = acc.len();
+        self.0.encode_to(acc);
+
+        for byte in &mut acc[start..] {
+            *byte = !*byte;
+        }
+    }
+}
+
+impl<T: Decode> Decode for Desc<T> {
+    fn decode_from(bytes: &[u8]) -> Result<(Self, &[u8]), Error> {
+        let inverted = bytes.iter().map(|byte| !byte).collect::<Vec<_>>();
+        let (value, rest) = T::decode_from(&inverted)?;
+        let consumed = inverted.len() - rest.len();
+
+        Ok((Self(value), &bytes[consumed..]))
+    }
+}
+
+impl<T: Encode + ?Sized> Encode for &T {
+    fn encode_to(&self, acc: &mut Vec<u8>) {
+        (**self).encode_to(acc)
+    }
+}
+
+fn split_array<const N: usize>(bytes: &[u8]) -> Result<([u8; N], &[u8]), Error> {
+    bytes
+        .split_first_chunk::<N>()
+        .map(|(chunk, rest)| (*chunk, rest))
+        .ok_or(Error::UnexpectedEnd)
+}
+
+macro_rules! unsigned {
+    ($($t:ty),*) => {
+        $(
+            impl Encode for $t {
+                fn encode_to(&self, acc: &mut Vec<u8>) {
+                    acc.extend_from_slice(&self.to_be_bytes());
+                }
+            }
+
+            impl Decode for $t {
+                fn decode_from(bytes: &[u8]) -> Result<(Self, &[u8]), Error> {
+                    let (chunk, rest) = split_array(bytes)?;
+                    Ok((<$t>::from_be_bytes(chunk), rest))
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! signed {
+    ($($t:ty => $u:ty),*) => {
+        $(
+            impl Encode for $t {
+                fn encode_to(&self, acc: &mut Vec<u8>) {
+                    ((*self as $u) ^ (1 << (<$u>::BITS - 1))).encode_to(acc);
+                }
+            }
+
+            impl Decode for $t {
+                fn decode_from(bytes: &[u8]) -> Result<(Self, &[u8]), Error> {
+                    let (value, rest) = <$u>::decode_from(bytes)?;
+                    Ok(((value ^ (1 << (<$u>::BITS - 1))) as $t, rest))
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! float {
+    ($($t:ty => $u:ty),*) => {
+        $(
+            impl Encode for $t {
+                fn encode_to(&self, acc: &mut Vec<u8>) {
+                    let bits = self.to_bits();
+                    let sign = 1 << (<$u>::BITS - 1);
+                    (if bits & sign == 0 { bits | sign } else { !bits }).encode_to(acc);
+                }
+            }
+
+            impl Decode for $t {
+                fn decode_from(bytes: &[u8]) -> Result<(Self, &[u8]), Error> {
+                    let (bits, rest) = <$u>::decode_from(bytes)?;
+                    let sign = 1 << (<$u>::BITS - 1);
+                    let bits = if bits & sign == 0 { !bits } else { bits & !sign };
+                    Ok((<$t>::from_bits(bits), rest))
+                }
+            }
+        )*
+    };
+}
+
+unsigned!(u8, u16, u32, u64, u128);
+signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+float!(f32 => u32, f64 => u64);
+
+impl Encode for bool {
+    fn encode_to(&self, acc: &mut Vec<u8>) {
+        acc.push(u8::from(*self));
+    }
+}
+
+impl Decode for bool {
+    fn decode_from(bytes: &[u8]) -> Result<(Self, &[u8]), Error> {
+        match bytes.split_first() {
+            Some((0, rest)) => Ok((false, rest)),
+            Some((1, rest)) => Ok((true, rest)),
+            Some(_) => Err(Error::InvalidComponent),
+            None => Err(Error::UnexpectedEnd),
+        }
+    }
+}
+
+impl Encode for [u8] {
+    fn encode_to(&self, acc: &mut Vec<u8>) {
+        for byte in self {
+            acc.push(*byte);
+            if *byte == ESCAPE {
+                acc.push(ESCAPED_NUL);
+            }
+        }
+        acc.extend_from_slice(&[ESCAPE, END]);
+    }
+}
+
+impl Encode for Vec<u8> {
+    fn encode_to(&self, acc: &mut Vec<u8>) {
+        self.as_slice().encode_to(acc)
+    }
+}
+
+impl Decode for Vec<u8> {
+    fn decode_from(mut bytes: &[u8]) -> Result<(Self, &[u8]), Error> {
+        let mut value = vec![];
+
+        loop {
+            let (byte, rest) = bytes.split_first().ok_or(Error::UnexpectedEnd)?;
+            bytes = rest;
+
+            if *byte == ESCAPE {
+                let (marker, rest) = bytes.split_first().ok_or(Error::UnexpectedEnd)?;
+                bytes = rest;
+
+                match *marker {
+                    ESCAPED_NUL => value.push(ESCAPE),
+                    END => return Ok((value, bytes)),
+                    _ => return Err(Error::InvalidComponent),
+                }
+            } else {
+                value.push(*byte);
+            }
+        }
+    }
+}
+
+impl Encode for str {
+    fn encode_to(&self, acc: &mut Vec<u8>) {
+        self.as_bytes().encode_to(acc)
+    }
+}
+
+impl Encode for String {
+    fn encode_to(&self, acc: &mut Vec<u8>) {
+        self.as_str().encode_to(acc)
+    }
+}
+
+impl Decode for String {
+    fn decode_from(bytes: &[u8]) -> Result<(Self, &[u8]), Error> {
+        let (value, rest) = Vec::<u8>::decode_from(bytes)?;
+        Ok((String::from_utf8(value)?, rest))
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode_to(&self, acc: &mut Vec<u8>) {
+        match self {
+            None => acc.push(NONE),
+            Some(value) => {
+                acc.push(SOME);
+                value.encode_to(acc);
+            }
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode_from(bytes: &[u8]) -> Result<(Self, &[u8]), Error> {
+        match bytes.split_first() {
+            Some((&NONE, rest)) => Ok((None, rest)),
+            Some((&SOME, rest)) => {
+                let (value, rest) = T::decode_from(rest)?;
+                Ok((Some(value), rest))
+            }
+            Some(_) => Err(Error::InvalidComponent),
+            None => Err(Error::UnexpectedEnd),
+        }
+    }
+}
+
+/// Timestamps are encoded as seconds since the epoch followed by subsecond nanoseconds
+impl Encode for DateTime<Utc> {
+    fn encode_to(&self, acc: &mut Vec<u8>) {
+        self.timestamp().encode_to(acc);
+        self.timestamp_subsec_nanos().encode_to(acc);
+    }
+}
+
+impl Decode for DateTime<Utc> {
+    fn decode_from(bytes: &[u8]) -> Result<(Self, &[u8]), Error> {
+        let (seconds, rest) = i64::decode_from(bytes)?;
+        let (nanoseconds, rest) = u32::decode_from(rest)?;
+        let value =
+            DateTime::from_timestamp(seconds, nanoseconds).ok_or(Error::InvalidComponent)?;
+
+        Ok((value, rest))
+    }
+}
+
+/// Dates are encoded as days since the start of the common era
+impl Encode for NaiveDate {
+    fn encode_to(&self, acc: &mut Vec<u8>) {
+        self.num_days_from_ce().encode_to(acc);
+    }
+}
+
+impl Decode for NaiveDate {
+    fn decode_from(bytes: &[u8]) -> Result<(Self, &[u8]), Error> {
+        let (days, rest) = i32::decode_from(bytes)?;
+        let value = NaiveDate::from_num_days_from_ce_opt(days).ok_or(Error::InvalidComponent)?;
+
+        Ok((value, rest))
+    }
+}
+
+macro_rules! tuple {
+    ($($name:ident),+) => {
+        impl<$($name: Encode),+> Encode for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode_to(&self, acc: &mut Vec<u8>) {
+                let ($($name,)+) = self;
+                $($name.encode_to(acc);)+
+            }
+        }
+
+        impl<$($name: Decode),+> Decode for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn decode_from(rest: &[u8]) -> Result<(Self, &[u8]), Error> {
+                $(let ($name, rest) = $name::decode_from(rest)?;)+
+                Ok((($($name,)+), rest))
+            }
+        }
+    };
+}
+
+tuple!(A);
+tuple!(A, B);
+tuple!(A, B, C);
+tuple!(A, B, C, D);
+tuple!(A, B, C, D, E);
+tuple!(A, B, C, D, E, F);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::Debug;
+
+    /// Check that values given in increasing order round-trip and encode in increasing order
+    fn assert_ordered<T: Encode + Decode + Debug + PartialEq>(values: &[T]) {
+        let encoded = values.iter().map(encode).collect::<Vec<_>>();
+
+        for (value, bytes) in values.iter().zip(&encoded) {
+            assert_eq!(&decode::<T>(bytes).unwrap(), value);
+        }
+
+        for (index, pair) in encoded.windows(2).enumerate() {
+            assert!(
+                pair[0] < pair[1],
+                "{:?} < {:?}",
+                values[index],
+                values[index + 1]
+            );
+        }
+    }
+
+    #[test]
+    fn signed_integers() {
+        assert_ordered(&[i8::MIN, i8::MIN + 1, -1, 0, 1, i8::MAX - 1, i8::MAX]);
+        assert_ordered(&[i32::MIN, -65_536, -1, 0, 1, 65_536, i32::MAX]);
+        assert_ordered(&[i64::MIN, i64::from(i32::MIN) - 1, -1, 0, 1, i64::MAX]);
+        assert_ordered(&[i128::MIN, -1, 0, 1, i128::MAX]);
+    }
+
+    #[test]
+    fn unsigned_integers() {
+        assert_ordered(&[0_u8, 1, 0x7f, 0x80, u8::MAX]);
+        assert_ordered(&[0_u64, 1, 0xff, 0x100, u64::MAX]);
+    }
+
+    #[test]
+    fn floats() {
+        let values = [
+            f64::NEG_INFINITY,
+            f64::MIN,
+            -1.5,
+            -f64::MIN_POSITIVE,
+            -0.0,
+            0.0,
+            f64::MIN_POSITIVE,
+            1.5,
+            f64::MAX,
+            f64::INFINITY,
+        ];
+
+        assert_ordered(&values);
+        assert_ordered(&[f32::NEG_INFINITY, -1.0, -0.0, 0.0, 1.0, f32::INFINITY]);
+
+        // Negative zero is not equal to zero as a key
+        for value in values {
+            let decoded = decode::<f64>(&encode(&value)).unwrap();
+            assert_eq!(decoded.to_bits(), value.to_bits());
+        }
+
+        for pair in values.windows(2) {
+            assert_eq!(pair[0].total_cmp(&pair[1]), std::cmp::Ordering::Less);
+        }
+    }
+
+    #[test]
+    fn bytes() {
+        assert_ordered(&[
+            vec![],
+            vec![0x00],
+            vec![0x00, 0x00],
+            vec![0x00, 0x01],
+            vec![0x01],
+            b"a".to_vec(),
+            b"a\x00".to_vec(),
+            b"a\x00b".to_vec(),
+            b"a\x01".to_vec(),
+            b"ab".to_vec(),
+            vec![0xff],
+            vec![0xff, 0x00],
+        ]);
+    }
+
+    #[test]
+    fn bytes_in_tuples() {
+        // A shorter component sorts first regardless of what follows it
+        assert_ordered(&[
+            (b"a".to_vec(), vec![0xff]),
+            (b"a\x00".to_vec(), vec![]),
+            (b"ab".to_vec(), vec![]),
+        ]);
+        assert_eq!(
+            decode::<(Vec<u8>, Vec<u8>)>(&encode(&(&b"\x00"[..], &b"\x00\xff"[..]))).unwrap(),
+            (vec![0x00], vec![0x00, 0xff])
+        );
+    }
+
+    #[test]
+    fn descending() {
+        assert_ordered(&[Desc(i64::MAX), Desc(0), Desc(-1), Desc(i64::MIN)]);
+        assert_ordered(&[
+            Desc(f64::INFINITY),
+            Desc(0.0),
+            Desc(-0.0),
+            Desc(f64::NEG_INFINITY),
+        ]);
+        assert_ordered(&[
+            Desc("ab".to_string()),
+            Desc("a\u{0}".to_string()),
+            Desc("a".to_string()),
+            Desc(String::new()),
+        ]);
+        assert_ordered(&[
+            (Desc(b"b".to_vec()), 0_u8),
+            (Desc(b"a\x00".to_vec()), 1),
+            (Desc(b"a".to_vec()), 0),
+            (Desc(b"a".to_vec()), 1),
+        ]);
+    }
+
+    #[test]
+    fn options() {
+        assert_ordered(&[None, Some(i32::MIN), Some(0), Some(i32::MAX)]);
+        assert_ordered(&[None, Some(String::new()), Some("a".to_string())]);
+        assert_ordered(&[Some(None), Some(Some(false)), Some(Some(true))]);
+        assert_ordered(&[Desc(Some(1_u8)), Desc(Some(0)), Desc(None)]);
+    }
+
+    #[test]
+    fn timestamps() {
+        let timestamp =
+            |seconds, nanoseconds| DateTime::from_timestamp(seconds, nanoseconds).unwrap();
+
+        assert_ordered(&[
+            DateTime::<Utc>::MIN_UTC,
+            timestamp(-1, 0),
+            timestamp(-1, 999_999_999),
+            timestamp(0, 0),
+            timestamp(0, 1),
+            timestamp(1_700_000_000, 500),
+            DateTime::<Utc>::MAX_UTC,
+        ]);
+    }
+
+    #[test]
+    fn dates() {
+        let date = |year, month, day| NaiveDate::from_ymd_opt(year, month, day).unwrap();
+
+        assert_ordered(&[
+            NaiveDate::MIN,
+            date(-1, 12, 31),
+            date(0, 1, 1),
+            date(1, 1, 1),
+            date(1970, 1, 1),
+            date(2024, 2, 29),
+            NaiveDate::MAX,
+        ]);
+    }
+
+    #[test]
+    fn tuples() {
+        assert_ordered(&[
+            ("a".to_string(), -1_i64, None),
+            ("a".to_string(), -1, Some(0_u8)),
+            ("a".to_string(), 0, None),
+            ("a\u{0}".to_string(), i64::MIN, None),
+            ("b".to_string(), i64::MIN, None),
+        ]);
+        assert_ordered(&[
+            (0_u8, true, 0_i16, 0.5_f32, "x".to_string(), Desc(2_u32)),
+            (0, true, 0, 0.5, "x".to_string(), Desc(1)),
+            (0, true, 1, -0.5, String::new(), Desc(3)),
+        ]);
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert_eq!(decode::<u32>(&[0, 0, 0]), Err(Error::UnexpectedEnd));
+        assert_eq!(decode::<u8>(&[0, 0]), Err(Error::TrailingBytes(1)));
+        assert_eq!(decode::<Vec<u8>>(b"a"), Err(Error::UnexpectedEnd));
+        assert_eq!(
+            decode::<Vec<u8>>(&[0x00, 0x01]),
+            Err(Error::InvalidComponent)
+        );
+        assert_eq!(decode::<bool>(&[2]), Err(Error::InvalidComponent));
+        assert_eq!(decode::<Option<u8>>(&[2, 0]), Err(Error::InvalidComponent));
+    }
+}
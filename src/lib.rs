@@ -1,11 +1,21 @@
+use lines::RecordDelimiter;
+use serde::Serialize;
+use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
 
+pub mod check;
 pub mod cli;
+#[cfg(feature = "rocksdb")]
 pub mod db;
+pub mod format;
+pub mod key;
 pub mod lines;
+pub mod policy;
 mod progress;
 pub mod report;
 pub mod session;
+pub mod store;
+pub mod tree;
 
 pub trait Format {
     type Error;
@@ -21,33 +31,211 @@ pub trait Format {
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+/// A format whose behavior may depend on runtime configuration
+///
+/// Static [`Format`] implementations can be used anywhere a runtime format is expected through
+/// the [`Static`] adapter.
+pub trait RuntimeFormat {
+    type Error;
+
+    fn key(&self, line: &str) -> Result<Vec<u8>, Self::Error>;
+    fn path(&self, key: &[u8]) -> Result<PathBuf, Self::Error>;
+
+    /// Compute the key for a raw line
+    ///
+    /// The default implementation requires the line to be valid UTF-8 and delegates to `key`, but
+    /// formats that can key arbitrary bytes may override it.
+    fn key_bytes(&self, line: &[u8]) -> Result<Vec<u8>, KeyError<Self::Error>> {
+        let line = std::str::from_utf8(line)?;
+        self.key(line).map_err(KeyError::Format)
+    }
+
+    fn is_input_recursive(&self) -> bool {
+        false
+    }
+
+    /// Whether to read a file found in an input directory or an archive member
+    ///
+    /// Archives found in input directories are always opened, and only their members are filtered.
+    fn include(&self, _path: &Path) -> bool {
+        true
+    }
+
+    /// The record delimiter to use when the session does not specify one
+    fn record_delimiter(&self) -> RecordDelimiter {
+        RecordDelimiter::Newline
+    }
+
+    /// Whether the first line of each input file is a header rather than a record
+    fn has_header(&self) -> bool {
+        false
+    }
+    /// Called with the header line of each input file before any of its records are keyed
+    fn read_header(&self, _line: &str) -> Result<(), Self::Error> {
+        Ok(())
+    }
+    /// A header line to write at the top of the output file at the given path
+    fn header(&self, _path: &Path) -> Option<String> {
+        None
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum KeyError<F> {
+    #[error("UTF-8 decoding error")]
+    Utf8(#[from] std::str::Utf8Error),
+    #[error("Format error")]
+    Format(F),
+}
+
+/// Adapts a static [`Format`] to [`RuntimeFormat`]
+pub struct Static<F>(PhantomData<fn() -> F>);
+
+impl<F> Static<F> {
+    pub fn new() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<F> Clone for Static<F> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<F> Copy for Static<F> {}
+
+impl<F> Default for Static<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F> std::fmt::Debug for Static<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Static<{}>", std::any::type_name::<F>())
+    }
+}
+
+impl<F: Format> RuntimeFormat for Static<F> {
+    type Error = F::Error;
+
+    fn key(&self, line: &str) -> Result<Vec<u8>, Self::Error> {
+        F::key(line)
+    }
+
+    fn path(&self, key: &[u8]) -> Result<PathBuf, Self::Error> {
+        F::path(key)
+    }
+
+    fn is_input_recursive(&self) -> bool {
+        F::is_input_recursive()
+    }
+
+    fn include(&self, path: &Path) -> bool {
+        F::include(path)
+    }
+}
+
+impl<F: RuntimeFormat + ?Sized> RuntimeFormat for std::sync::Arc<F> {
+    type Error = F::Error;
+
+    fn key(&self, line: &str) -> Result<Vec<u8>, Self::Error> {
+        (**self).key(line)
+    }
+
+    fn path(&self, key: &[u8]) -> Result<PathBuf, Self::Error> {
+        (**self).path(key)
+    }
+
+    fn key_bytes(&self, line: &[u8]) -> Result<Vec<u8>, KeyError<Self::Error>> {
+        (**self).key_bytes(line)
+    }
+
+    fn is_input_recursive(&self) -> bool {
+        (**self).is_input_recursive()
+    }
+
+    fn include(&self, path: &Path) -> bool {
+        (**self).include(path)
+    }
+
+    fn record_delimiter(&self) -> RecordDelimiter {
+        (**self).record_delimiter()
+    }
+
+    fn has_header(&self) -> bool {
+        (**self).has_header()
+    }
+
+    fn read_header(&self, line: &str) -> Result<(), Self::Error> {
+        (**self).read_header(line)
+    }
+
+    fn header(&self, path: &Path) -> Option<String> {
+        (**self).header(path)
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
 pub struct Location {
+    #[serde(serialize_with = "report::serialize_path")]
     pub path: PathBuf,
+    /// The archive member name, if the input is a member of an archive
+    pub member: Option<String>,
+    /// The line on which the record starts
     pub line_number: usize,
+    /// The offset of the start of the record in the (decompressed) input
+    pub byte_offset: u64,
 }
 
 impl Location {
-    pub fn new<P: AsRef<Path>>(path: P, line_number: usize) -> Self {
+    pub fn new<P: AsRef<Path>>(path: P, line_number: usize, byte_offset: u64) -> Self {
         Self {
             path: path.as_ref().to_owned(),
+            member: None,
             line_number,
+            byte_offset,
         }
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
 pub struct Replacement {
-    pub old_value: String,
-    pub new_value: String,
+    #[serde(serialize_with = "report::serialize_lossy")]
+    pub old_value: Vec<u8>,
+    #[serde(serialize_with = "report::serialize_lossy")]
+    pub new_value: Vec<u8>,
+    pub kept: Kept,
+}
+
+/// The value that was stored after a collision was resolved
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kept {
+    Old,
+    New,
+    Merged(#[serde(serialize_with = "report::serialize_lossy")] Vec<u8>),
+}
+
+impl Replacement {
+    pub fn kept_value(&self) -> &[u8] {
+        match &self.kept {
+            Kept::Old => &self.old_value,
+            Kept::New => &self.new_value,
+            Kept::Merged(value) => value,
+        }
+    }
 }
 
 /// An instance of a repeated key
 ///
 /// May be either a duplicate (the line values are the same) or a collision (the line values differ)
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
 pub struct Repeat {
     pub location: Location,
+    /// The location of the earliest occurrence of the key
+    pub first: Location,
     pub replacement: Option<Replacement>,
 }
 
@@ -1,11 +1,75 @@
 use crate::Location;
-use flate2::read::GzDecoder;
+use bzip2::bufread::MultiBzDecoder;
+use flate2::bufread::MultiGzDecoder;
+use lz4_flex::frame::FrameDecoder;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Lines};
+use std::io::{BufRead, BufReader, Read};
 use std::path::{Path, PathBuf};
+use xz2::bufread::XzDecoder;
 use zstd::stream::read::Decoder as ZstDecoder;
 
-type LineResult = Result<(usize, String), Error>;
+/// The input path used to indicate standard input
+pub const STDIN_PATH: &str = "-";
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const BZIP2_MAGIC: [u8; 3] = *b"BZh";
+const BZIP2_BLOCK_MAGIC: [u8; 6] = [0x31, 0x41, 0x59, 0x26, 0x53, 0x59];
+const BZIP2_END_MAGIC: [u8; 6] = [0x17, 0x72, 0x45, 0x38, 0x50, 0x90];
+const XZ_MAGIC: [u8; 6] = [0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00];
+const LZ4_MAGIC: [u8; 4] = [0x04, 0x22, 0x4d, 0x18];
+
+type RecordResult = Result<Record, Error>;
+
+/// Determines how an input stream is split into records
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum RecordDelimiter {
+    /// One record per line (`\n` or `\r\n`)
+    #[default]
+    Newline,
+    /// Records terminated by NUL bytes
+    Nul,
+    /// CSV records, where line breaks inside double-quoted fields do not end the record
+    ///
+    /// As in the CSV format's field parser, a quote only opens a quoted field at the start of a
+    /// field, and is otherwise an ordinary character.
+    Csv { field_delimiter: char },
+    /// Blocks of non-blank lines separated by one or more blank lines
+    BlankLine,
+}
+
+impl RecordDelimiter {
+    pub const NAMES: [&'static str; 4] = ["newline", "nul", "csv", "blank-line"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "newline" => Some(Self::Newline),
+            "nul" => Some(Self::Nul),
+            "csv" => Some(Self::Csv {
+                field_delimiter: ',',
+            }),
+            "blank-line" => Some(Self::BlankLine),
+            _ => None,
+        }
+    }
+
+    /// The bytes written after each record in output files
+    pub fn terminator(&self) -> &'static [u8] {
+        match self {
+            Self::Newline | Self::Csv { .. } => b"\n",
+            Self::Nul => b"\0",
+            Self::BlankLine => b"\n\n",
+        }
+    }
+}
+
+/// A record with the line number and byte offset at which it starts
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Record {
+    pub line_number: usize,
+    pub byte_offset: u64,
+    pub bytes: Vec<u8>,
+}
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -21,78 +85,549 @@ pub enum Error {
     },
     #[error("Invalid path")]
     InvalidPath(PathBuf),
+    #[error("Zip archive error")]
+    Zip {
+        path: PathBuf,
+        error: zip::result::ZipError,
+    },
 }
 
-pub fn lines<P: AsRef<Path>>(path: P) -> Result<Box<dyn Iterator<Item = LineResult>>, Error> {
-    let path = path.as_ref().to_path_buf();
+/// A logical input: a file, standard input, or a member of an archive
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Source {
+    pub path: PathBuf,
+    pub member: Option<String>,
+}
+
+impl Source {
+    pub fn file<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            member: None,
+        }
+    }
 
-    if path.is_file() {
-        if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
-            let file = File::open(&path).map_err(|error| Error::File {
-                path: path.clone(),
+    pub fn member<P: AsRef<Path>>(path: P, member: &str) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            member: Some(member.to_string()),
+        }
+    }
 
-                error,
-            })?;
+    pub fn location(&self, line_number: usize, byte_offset: u64) -> Location {
+        Location {
+            path: self.path.clone(),
+            member: self.member.clone(),
+            line_number,
+            byte_offset,
+        }
+    }
+}
 
-            match extension.to_ascii_lowercase().as_str() {
-                "gz" => {
-                    let reader = BufReader::new(GzDecoder::new(file));
-                    Ok(Box::new(LineReader::new(path, reader)))
-                }
-                "zst" => {
-                    let reader =
-                        BufReader::new(ZstDecoder::new(file).map_err(|error| Error::File {
-                            path: path.clone(),
-                            error,
-                        })?);
-                    Ok(Box::new(LineReader::new(path, reader)))
+/// An archive format whose members are read as separate inputs
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Archive {
+    /// A tar file, optionally compressed with any supported input compression format
+    Tar,
+    Zip,
+}
+
+impl Archive {
+    /// Identify an archive format from a file name
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let name = path.as_ref().file_name()?.to_str()?.to_ascii_lowercase();
+
+        if name.ends_with(".zip") {
+            Some(Self::Zip)
+        } else if name.ends_with(".tar")
+            || name.ends_with(".tgz")
+            || name.rsplit_once('.').is_some_and(|(stem, extension)| {
+                stem.ends_with(".tar") && Compression::from_extension(extension).is_some()
+            })
+        {
+            Some(Self::Tar)
+        } else {
+            None
+        }
+    }
+}
+
+/// Read the records of the included regular file members of an archive in archive order
+///
+/// Members are decompressed according to their own names and contents, and each is visited with
+/// its index among the regular file members of the archive. Excluded members are not decompressed.
+pub fn archive_records<P: AsRef<Path>, E: From<Error>, I, V>(
+    path: P,
+    archive: Archive,
+    delimiter: RecordDelimiter,
+    include: I,
+    mut visit: V,
+) -> Result<(), E>
+where
+    I: Fn(&str) -> bool,
+    V: FnMut(usize, &str, &mut dyn Iterator<Item = RecordResult>) -> Result<(), E>,
+{
+    let path = path.as_ref();
+    let mut member_index = 0;
+
+    visit_archive(path, archive, |name, reader| {
+        member_index += 1;
+
+        if !include(name) {
+            return Ok(());
+        }
+
+        let reader = decompress(Path::new(name), BufReader::new(reader))?;
+        let mut records = RecordReader::new(Source::member(path, name), reader, delimiter);
+
+        visit(member_index - 1, name, &mut records)
+    })
+}
+
+fn visit_archive<E: From<Error>, V>(path: &Path, archive: Archive, mut visit: V) -> Result<(), E>
+where
+    V: FnMut(&str, &mut dyn Read) -> Result<(), E>,
+{
+    let to_error = |error| Error::File {
+        path: path.to_path_buf(),
+        error,
+    };
+    let file = File::open(path).map_err(to_error)?;
+
+    match archive {
+        Archive::Tar => {
+            let mut archive = tar::Archive::new(decompress(path, BufReader::new(file))?);
+
+            for entry in archive.entries().map_err(to_error)? {
+                let mut entry = entry.map_err(to_error)?;
+
+                if entry.header().entry_type().is_file() {
+                    let name = entry
+                        .path()
+                        .map_err(to_error)?
+                        .to_string_lossy()
+                        .into_owned();
+                    visit(&name, &mut entry)?;
                 }
-                _ => {
-                    let reader = BufReader::new(file);
-                    Ok(Box::new(LineReader::new(path, reader)))
+            }
+        }
+        Archive::Zip => {
+            let to_zip_error = |error| Error::Zip {
+                path: path.to_path_buf(),
+                error,
+            };
+            let mut archive = zip::ZipArchive::new(file).map_err(to_zip_error)?;
+
+            for index in 0..archive.len() {
+                let mut entry = archive.by_index(index).map_err(to_zip_error)?;
+
+                if entry.is_file() {
+                    let name = entry.name().to_string();
+                    visit(&name, &mut entry)?;
                 }
             }
-        } else {
-            Err(Error::InvalidPath(path))
         }
+    }
+
+    Ok(())
+}
+
+pub fn lines<P: AsRef<Path>>(path: P) -> Result<Box<dyn Iterator<Item = RecordResult>>, Error> {
+    records(path, RecordDelimiter::Newline)
+}
+
+pub fn records<P: AsRef<Path>>(
+    path: P,
+    delimiter: RecordDelimiter,
+) -> Result<Box<dyn Iterator<Item = RecordResult>>, Error> {
+    let path = path.as_ref().to_path_buf();
+
+    if is_stdin(&path) {
+        let reader = decompress(&path, BufReader::new(std::io::stdin().lock()))?;
+        Ok(Box::new(RecordReader::new(
+            Source::file(path),
+            reader,
+            delimiter,
+        )))
+    } else if path.is_file() {
+        let file = File::open(&path).map_err(|error| Error::File {
+            path: path.clone(),
+            error,
+        })?;
+
+        let reader = decompress(&path, BufReader::new(file))?;
+        Ok(Box::new(RecordReader::new(
+            Source::file(path),
+            reader,
+            delimiter,
+        )))
     } else {
         Err(Error::InvalidPath(path))
     }
 }
 
-struct LineReader<B> {
-    lines: Lines<B>,
-    path: PathBuf,
+/// Indicates whether an input path refers to standard input
+pub fn is_stdin<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref() == Path::new(STDIN_PATH)
+}
+
+/// A compression format supported for input
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Bzip2,
+    Xz,
+    Lz4,
+}
+
+impl Compression {
+    /// Identify a compression format from the first bytes of a stream
+    pub fn from_magic(header: &[u8]) -> Option<Self> {
+        if header.starts_with(&GZIP_MAGIC) {
+            Some(Self::Gzip)
+        } else if header.starts_with(&ZSTD_MAGIC) {
+            Some(Self::Zstd)
+        } else if is_bzip2(header) {
+            Some(Self::Bzip2)
+        } else if header.starts_with(&XZ_MAGIC) {
+            Some(Self::Xz)
+        } else if header.starts_with(&LZ4_MAGIC) {
+            Some(Self::Lz4)
+        } else {
+            None
+        }
+    }
+
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension.to_ascii_lowercase().as_str() {
+            "gz" => Some(Self::Gzip),
+            "zst" => Some(Self::Zstd),
+            "bz2" => Some(Self::Bzip2),
+            "xz" => Some(Self::Xz),
+            "lz4" => Some(Self::Lz4),
+            _ => None,
+        }
+    }
+
+    /// Wrap a reader in a decoder for this format (concatenated members or frames are all read)
+    pub fn decoder<'a, R: BufRead + 'a>(
+        &self,
+        reader: R,
+    ) -> Result<Box<dyn BufRead + 'a>, std::io::Error> {
+        Ok(match self {
+            Self::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+            Self::Zstd => Box::new(BufReader::new(ZstDecoder::with_buffer(reader)?)),
+            Self::Bzip2 => Box::new(BufReader::new(MultiBzDecoder::new(reader))),
+            Self::Xz => Box::new(BufReader::new(XzDecoder::new_multi_decoder(reader))),
+            Self::Lz4 => Box::new(BufReader::new(FrameDecoder::new(reader))),
+        })
+    }
+}
+
+/// Whether a stream starts with a bzip2 header (with a block size level) followed by either a block
+/// or the end of the stream, since text may also start with the header's magic
+fn is_bzip2(header: &[u8]) -> bool {
+    header.starts_with(&BZIP2_MAGIC)
+        && header
+            .get(3)
+            .is_some_and(|level| (b'1'..=b'9').contains(level))
+        && header
+            .get(4..10)
+            .is_some_and(|magic| magic == BZIP2_BLOCK_MAGIC || magic == BZIP2_END_MAGIC)
+}
+
+/// Wrap a reader in a decoder, detecting the compression format from the first bytes of the
+/// stream or (if they are not recognized) the path's extension
+fn decompress<'a, R: BufRead + 'a>(
+    path: &Path,
+    mut reader: R,
+) -> Result<Box<dyn BufRead + 'a>, Error> {
+    let to_error = |error| Error::File {
+        path: path.to_path_buf(),
+        error,
+    };
+
+    let compression = Compression::from_magic(reader.fill_buf().map_err(to_error)?).or_else(|| {
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(Compression::from_extension)
+    });
+
+    match compression {
+        Some(compression) => compression.decoder(reader).map_err(to_error),
+        None => Ok(Box::new(reader)),
+    }
+}
+
+/// Reads delimited records as bytes, stripping the delimiter
+///
+/// Reading stops after the first error.
+struct RecordReader<B> {
+    reader: B,
+    source: Source,
+    delimiter: RecordDelimiter,
     line_number: usize,
+    byte_offset: u64,
+    failed: bool,
 }
 
-impl<B: BufRead> LineReader<B> {
-    fn new(path: PathBuf, reader: B) -> Self {
+impl<B: BufRead> RecordReader<B> {
+    fn new(source: Source, reader: B, delimiter: RecordDelimiter) -> Self {
         Self {
-            lines: reader.lines(),
-            path,
+            reader,
+            source,
+            delimiter,
+            line_number: 1,
+            byte_offset: 0,
+            failed: false,
+        }
+    }
+
+    /// Read one line (including its terminator) onto the end of the buffer
+    fn read_line(&mut self, buffer: &mut Vec<u8>) -> std::io::Result<usize> {
+        let count = self.reader.read_until(b'\n', buffer)?;
+        self.byte_offset += count as u64;
+        Ok(count)
+    }
+
+    fn read_record(&mut self) -> std::io::Result<Option<Record>> {
+        let mut record = Record {
+            line_number: self.line_number,
+            byte_offset: self.byte_offset,
+            bytes: vec![],
+        };
+
+        match self.delimiter {
+            RecordDelimiter::Newline => {
+                if self.read_line(&mut record.bytes)? == 0 {
+                    return Ok(None);
+                }
+            }
+            RecordDelimiter::Nul => {
+                let count = self.reader.read_until(0, &mut record.bytes)?;
+                if count == 0 {
+                    return Ok(None);
+                }
+                self.byte_offset += count as u64;
+            }
+            RecordDelimiter::Csv { field_delimiter } => {
+                let mut encoded = [0; 4];
+                let field_delimiter = field_delimiter.encode_utf8(&mut encoded).as_bytes();
+                let mut quote = CsvQuote::FieldStart;
 
-            line_number: 0,
+                loop {
+                    let start = record.bytes.len();
+
+                    if self.read_line(&mut record.bytes)? == 0 {
+                        if start == 0 {
+                            return Ok(None);
+                        }
+                        break;
+                    }
+
+                    for end in start + 1..=record.bytes.len() {
+                        quote = quote.next(&record.bytes[start..end], field_delimiter);
+                    }
+
+                    if quote != CsvQuote::Quoted {
+                        break;
+                    }
+                }
+            }
+            RecordDelimiter::BlankLine => loop {
+                let start = record.bytes.len();
+                if self.read_line(&mut record.bytes)? == 0 {
+                    if record.bytes.is_empty() {
+                        return Ok(None);
+                    }
+                    break;
+                }
+
+                if is_blank(&record.bytes[start..]) {
+                    if start == 0 {
+                        // Skip blank lines before the block
+                        self.line_number += 1;
+                        record.line_number = self.line_number;
+                        record.byte_offset = self.byte_offset;
+                        record.bytes.clear();
+                    } else {
+                        self.line_number += 1;
+                        record.bytes.truncate(start);
+                        break;
+                    }
+                }
+            },
         }
+
+        self.line_number += bytecount(&record.bytes, b'\n');
+
+        let terminator = if self.delimiter == RecordDelimiter::Nul {
+            b'\0'
+        } else {
+            b'\n'
+        };
+
+        if record.bytes.last() == Some(&terminator) {
+            record.bytes.pop();
+            if terminator == b'\n' && record.bytes.last() == Some(&b'\r') {
+                record.bytes.pop();
+            }
+        }
+
+        Ok(Some(record))
     }
 }
 
-impl<B: BufRead> Iterator for LineReader<B> {
-    type Item = Result<(usize, String), Error>;
+impl<B: BufRead> Iterator for RecordReader<B> {
+    type Item = RecordResult;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.lines.next().map(|result| {
-            self.line_number += 1;
-            match result {
-                Ok(line) => Ok((self.line_number, line)),
-                Err(error) => Err(Error::Line {
-                    location: Location {
-                        path: self.path.clone(),
-                        line_number: self.line_number,
-                    },
+        if self.failed {
+            return None;
+        }
+
+        let line_number = self.line_number;
+        let byte_offset = self.byte_offset;
+
+        self.read_record().transpose().map(|result| {
+            result.map_err(|error| {
+                self.failed = true;
+
+                Error::Line {
+                    location: self.source.location(line_number, byte_offset),
                     error,
-                }),
-            }
+                }
+            })
         })
     }
 }
+
+impl<B: BufRead> std::iter::FusedIterator for RecordReader<B> {}
+
+/// The quoting state of a CSV record as it is read
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum CsvQuote {
+    FieldStart,
+    Unquoted,
+    Quoted,
+    /// A quote in a quoted field, which either ends the field or is doubled
+    QuoteInQuoted,
+}
+
+impl CsvQuote {
+    /// The state after the last byte of a line read so far
+    fn next(self, line: &[u8], field_delimiter: &[u8]) -> Self {
+        let quote = line.last() == Some(&b'"');
+
+        match self {
+            Self::Quoted if quote => Self::QuoteInQuoted,
+            Self::Quoted => Self::Quoted,
+            Self::QuoteInQuoted if quote => Self::Quoted,
+            Self::FieldStart if quote => Self::Quoted,
+            _ if line.ends_with(field_delimiter) => Self::FieldStart,
+            _ => Self::Unquoted,
+        }
+    }
+}
+
+fn bytecount(bytes: &[u8], target: u8) -> usize {
+    bytes.iter().filter(|byte| **byte == target).count()
+}
+
+fn is_blank(line: &[u8]) -> bool {
+    line.iter().all(|byte| byte.is_ascii_whitespace())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(input: &[u8], delimiter: RecordDelimiter) -> Vec<(usize, u64, String)> {
+        RecordReader::new(Source::file("test"), input, delimiter)
+            .map(|record| {
+                let record = record.unwrap();
+                let bytes = String::from_utf8(record.bytes).unwrap();
+
+                (record.line_number, record.byte_offset, bytes)
+            })
+            .collect()
+    }
+
+    fn records(expected: &[(usize, u64, &str)]) -> Vec<(usize, u64, String)> {
+        expected
+            .iter()
+            .map(|(line_number, byte_offset, bytes)| {
+                (*line_number, *byte_offset, bytes.to_string())
+            })
+            .collect()
+    }
+
+    const CSV: RecordDelimiter = RecordDelimiter::Csv {
+        field_delimiter: ',',
+    };
+
+    #[test]
+    fn newline_records() {
+        assert_eq!(
+            read(b"a\r\n\nbc\nd", RecordDelimiter::Newline),
+            records(&[(1, 0, "a"), (2, 3, ""), (3, 4, "bc"), (4, 7, "d")])
+        );
+    }
+
+    #[test]
+    fn nul_records() {
+        assert_eq!(
+            read(b"a\nb\0c\0\0d\ne\n", RecordDelimiter::Nul),
+            records(&[(1, 0, "a\nb"), (2, 4, "c"), (2, 6, ""), (2, 7, "d\ne\n")])
+        );
+    }
+
+    #[test]
+    fn csv_quoted_line_breaks() {
+        assert_eq!(
+            read(b"a,\"b\nc\"\r\n\"d\"\"\n\",e\nf,g", CSV),
+            records(&[
+                (1, 0, "a,\"b\nc\""),
+                (3, 9, "\"d\"\"\n\",e"),
+                (5, 18, "f,g")
+            ])
+        );
+    }
+
+    #[test]
+    fn csv_quotes_inside_unquoted_fields() {
+        assert_eq!(
+            read(b"a,5\" screen\nb,\"c\"d\ne\n", CSV),
+            records(&[(1, 0, "a,5\" screen"), (2, 12, "b,\"c\"d"), (3, 19, "e")])
+        );
+    }
+
+    #[test]
+    fn csv_unterminated_quote() {
+        assert_eq!(
+            read(b"a\n\"b\nc", CSV),
+            records(&[(1, 0, "a"), (2, 2, "\"b\nc")])
+        );
+    }
+
+    #[test]
+    fn csv_field_delimiter() {
+        let delimiter = RecordDelimiter::Csv {
+            field_delimiter: '\t',
+        };
+
+        assert_eq!(
+            read(b"a,\"b\n\t\"c\nd\"\n", delimiter),
+            records(&[(1, 0, "a,\"b"), (2, 5, "\t\"c\nd\"")])
+        );
+    }
+
+    #[test]
+    fn blank_line_records() {
+        assert_eq!(
+            read(b"\n \na\nb\n\n\n\nc\r\n\r\nd", RecordDelimiter::BlankLine),
+            records(&[(3, 3, "a\nb"), (8, 10, "c"), (10, 15, "d")])
+        );
+    }
+}
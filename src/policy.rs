@@ -0,0 +1,102 @@
+use crate::Kept;
+use std::sync::Arc;
+
+type MergeFn = dyn Fn(&[u8], &[u8]) -> Vec<u8> + Send + Sync;
+
+/// Determines which value is stored when two different lines share a key
+#[derive(Clone, Default)]
+pub enum CollisionPolicy {
+    /// Keep the value that was inserted first
+    KeepFirst,
+    /// Keep the value that was inserted last
+    #[default]
+    KeepLast,
+    /// Keep the longer value (the earlier value wins ties)
+    KeepLongest,
+    /// Abort the session on the first collision
+    Fail,
+    /// Store the result of a user-supplied function applied to the old and new values
+    Merge(Arc<MergeFn>),
+}
+
+impl CollisionPolicy {
+    pub const NAMES: [&'static str; 4] = ["first", "last", "longest", "fail"];
+
+    pub fn merge<M: Fn(&[u8], &[u8]) -> Vec<u8> + Send + Sync + 'static>(merge: M) -> Self {
+        Self::Merge(Arc::new(merge))
+    }
+
+    /// Look up a policy by its command-line name (merge policies have no name)
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "first" => Some(Self::KeepFirst),
+            "last" => Some(Self::KeepLast),
+            "longest" => Some(Self::KeepLongest),
+            "fail" => Some(Self::Fail),
+            _ => None,
+        }
+    }
+
+    /// Decide which value to keep, or return `None` if the collision should be treated as an error
+    pub fn resolve(&self, old_value: &[u8], new_value: &[u8]) -> Option<Kept> {
+        match self {
+            Self::KeepFirst => Some(Kept::Old),
+            Self::KeepLast => Some(Kept::New),
+            Self::KeepLongest => Some(if new_value.len() > old_value.len() {
+                Kept::New
+            } else {
+                Kept::Old
+            }),
+            Self::Fail => None,
+            Self::Merge(merge) => Some(Kept::Merged(merge(old_value, new_value))),
+        }
+    }
+}
+
+impl std::fmt::Debug for CollisionPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::KeepFirst => write!(f, "KeepFirst"),
+            Self::KeepLast => write!(f, "KeepLast"),
+            Self::KeepLongest => write!(f, "KeepLongest"),
+            Self::Fail => write!(f, "Fail"),
+            Self::Merge(_) => write!(f, "Merge(..)"),
+        }
+    }
+}
+
+/// Determines which of two competing lines is considered to come first
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Precedence {
+    /// Lines are ordered by insertion time, which depends on task scheduling
+    #[default]
+    Arrival,
+    /// Lines are ordered by the position of their file in the sorted input list and their line
+    /// number, so the output does not depend on parallelism
+    Input,
+}
+
+/// Determines how lines that are not valid UTF-8 are handled by formats that require it
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Utf8Policy {
+    /// Abort the session
+    #[default]
+    Error,
+    /// Skip the line and record its location in the run report
+    Skip,
+    /// Replace invalid sequences with U+FFFD before keying and storing the line
+    Replace,
+}
+
+impl Utf8Policy {
+    pub const NAMES: [&'static str; 3] = ["error", "skip", "replace"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "error" => Some(Self::Error),
+            "skip" => Some(Self::Skip),
+            "replace" => Some(Self::Replace),
+            _ => None,
+        }
+    }
+}
@@ -5,8 +5,9 @@ const STYLE_PROGRESS_CHARS: &str = "##-";
 const READ_BAR_FINISH_MESSAGE: &str = "Reading finished";
 const WRITE_BAR_FINISH_MESSAGE: &str = "Writing finished";
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub enum ProgressState {
+    #[default]
     Empty,
     Diplayed {
         style: ProgressStyle,
@@ -16,12 +17,6 @@ pub enum ProgressState {
     },
 }
 
-impl Default for ProgressState {
-    fn default() -> Self {
-        Self::Empty
-    }
-}
-
 impl ProgressState {
     pub fn new() -> Self {
         let style = ProgressStyle::with_template(STYLE_TEMPLATE)
@@ -1,6 +1,14 @@
-use crate::Repeat;
-use std::collections::HashMap;
+use crate::{Repeat, lines::Source};
+use serde::{Serialize, Serializer, ser::SerializeStruct};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+type Encoder = zstd::stream::write::Encoder<'static, BufWriter<File>>;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct WriteReport {
@@ -31,24 +39,243 @@ impl WriteReport {
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+impl Serialize for WriteReport {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let file_counts = self
+            .file_counts
+            .iter()
+            .map(|(path, count)| (path.to_string_lossy(), count))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut state = serializer.serialize_struct("WriteReport", 3)?;
+        state.serialize_field("file_counts", &file_counts)?;
+        state.serialize_field("file_count", &self.file_count())?;
+        state.serialize_field("line_count", &self.line_count())?;
+        state.end()
+    }
+}
+
+/// The time taken by each phase of a session (serialized in seconds)
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct Timings {
+    /// Listing and reading the inputs (and merging any base files)
+    #[serde(serialize_with = "serialize_seconds")]
+    pub read: Duration,
+    /// Writing the output files
+    #[serde(serialize_with = "serialize_seconds")]
+    pub write: Duration,
+    #[serde(serialize_with = "serialize_seconds")]
+    pub total: Duration,
+}
+
+/// Statistics for a single input (or archive member) read by a session
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct InputStats {
+    #[serde(serialize_with = "serialize_path")]
+    pub path: PathBuf,
+    pub member: Option<String>,
+    /// Whether this is an existing output file merged in update mode, rather than an input
+    pub base: bool,
+    /// Records read (not including any header)
+    pub lines: usize,
+    /// Bytes in the records read (including any header, but not delimiters)
+    pub bytes: u64,
+    pub duplicates: usize,
+    pub collisions: usize,
+    /// Lines skipped because they were not valid UTF-8
+    ///
+    /// Only lines skipped under [`crate::policy::Utf8Policy::Skip`] are counted, since any other error
+    /// reading a key ends the session.
+    pub key_errors: usize,
+    /// Lines whose keys were first found in this input
+    ///
+    /// A repeat is counted for the later of two occurrences of a key, so this is zero if every
+    /// line was repeated from an earlier input.
+    pub new_keys: usize,
+    #[serde(serialize_with = "serialize_seconds")]
+    pub elapsed: Duration,
+}
+
+impl InputStats {
+    pub(crate) fn new(source: &Source) -> Self {
+        Self {
+            path: source.path.clone(),
+            member: source.member.clone(),
+            base: false,
+            lines: 0,
+            bytes: 0,
+            duplicates: 0,
+            collisions: 0,
+            key_errors: 0,
+            new_keys: 0,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    /// Fill in the count of new keys once every line and repeat has been counted
+    pub(crate) fn finish(self) -> Self {
+        Self {
+            new_keys: self
+                .lines
+                .saturating_sub(self.duplicates + self.collisions + self.key_errors),
+            ..self
+        }
+    }
+
+    pub(crate) fn add(&mut self, other: &Self) {
+        self.lines += other.lines;
+        self.bytes += other.bytes;
+        self.duplicates += other.duplicates;
+        self.collisions += other.collisions;
+        self.key_errors += other.key_errors;
+        self.elapsed += other.elapsed;
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
 pub struct RunReport {
-    pub repeats: Vec<Repeat>,
+    pub duplicates: usize,
+    pub collisions: usize,
+    /// The file the repeats were written to, if any
+    #[serde(serialize_with = "serialize_optional_path")]
+    pub repeats_path: Option<PathBuf>,
+    /// The number of lines skipped because they were not valid UTF-8
+    pub skipped: usize,
     pub write_report: WriteReport,
+    pub timings: Timings,
+    /// Statistics for each base file merged in update mode, followed by each input read by the
+    /// session, in input order
+    pub inputs: Vec<InputStats>,
 }
 
 impl RunReport {
     pub fn duplicate_count(&self) -> usize {
-        self.repeats
-            .iter()
-            .filter(|repeat| !repeat.is_collision())
-            .count()
+        self.duplicates
     }
 
     pub fn collision_count(&self) -> usize {
-        self.repeats
-            .iter()
-            .filter(|repeat| repeat.is_collision())
-            .count()
+        self.collisions
+    }
+}
+
+/// Where the repeats found by a session are sent as they are found
+#[derive(Clone, Default)]
+pub enum RepeatSink {
+    /// Only count the repeats
+    #[default]
+    Count,
+    /// Write the repeats to a file as ZSTD-compressed JSON lines
+    File(PathBuf),
+    /// Pass each repeat to a function, which may be called concurrently
+    Callback(Arc<dyn Fn(Repeat) + Send + Sync>),
+}
+
+/// Counts repeats and sends them to a sink
+pub(crate) struct RepeatWriter {
+    output: Output,
+    duplicates: AtomicUsize,
+    collisions: AtomicUsize,
+}
+
+enum Output {
+    Count,
+    File(PathBuf, Mutex<Option<Encoder>>),
+    Callback(Arc<dyn Fn(Repeat) + Send + Sync>),
+}
+
+impl RepeatWriter {
+    pub(crate) fn new(sink: RepeatSink) -> Result<Self, std::io::Error> {
+        let output = match sink {
+            RepeatSink::Count => Output::Count,
+            RepeatSink::File(path) => {
+                let file = BufWriter::new(File::create(&path)?);
+                Output::File(path, Mutex::new(Some(Encoder::new(file, 0)?)))
+            }
+            RepeatSink::Callback(callback) => Output::Callback(callback),
+        };
+
+        Ok(Self {
+            output,
+            duplicates: AtomicUsize::new(0),
+            collisions: AtomicUsize::new(0),
+        })
+    }
+
+    pub(crate) fn send(&self, repeat: Repeat) -> Result<(), std::io::Error> {
+        if repeat.is_collision() {
+            self.collisions.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.duplicates.fetch_add(1, Ordering::Relaxed);
+        }
+
+        match &self.output {
+            Output::Count => Ok(()),
+            Output::File(_, encoder) => {
+                let mut encoder = encoder
+                    .lock()
+                    .map_err(|_| std::io::Error::other("poisoned repeats writer"))?;
+                let encoder = encoder
+                    .as_mut()
+                    .ok_or_else(|| std::io::Error::other("repeats writer already finished"))?;
+                serde_json::to_writer(&mut *encoder, &repeat)?;
+                writeln!(encoder)
+            }
+            Output::Callback(callback) => {
+                callback(repeat);
+                Ok(())
+            }
+        }
+    }
+
+    /// Finish writing, returning the duplicate and collision counts and the path of the file
+    pub(crate) fn finish(&self) -> Result<(usize, usize, Option<PathBuf>), std::io::Error> {
+        let path = match &self.output {
+            Output::File(path, encoder) => {
+                let encoder = encoder
+                    .lock()
+                    .map_err(|_| std::io::Error::other("poisoned repeats writer"))?
+                    .take();
+
+                if let Some(encoder) = encoder {
+                    encoder.finish()?.flush()?;
+                }
+
+                Some(path.clone())
+            }
+            _ => None,
+        };
+
+        Ok((
+            self.duplicates.load(Ordering::Relaxed),
+            self.collisions.load(Ordering::Relaxed),
+            path,
+        ))
+    }
+}
+
+/// Serialize bytes as a string, replacing invalid UTF-8
+pub(crate) fn serialize_lossy<S: Serializer>(
+    bytes: &[u8],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&String::from_utf8_lossy(bytes))
+}
+
+/// Serialize a path as a string, replacing invalid Unicode
+pub(crate) fn serialize_path<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.to_string_lossy())
+}
+
+fn serialize_optional_path<S: Serializer>(
+    path: &Option<PathBuf>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match path {
+        Some(path) => serialize_path(path, serializer),
+        None => serializer.serialize_none(),
     }
 }
+
+fn serialize_seconds<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
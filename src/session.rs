@@ -1,16 +1,38 @@
+#[cfg(feature = "rocksdb")]
+use crate::db::LineDb;
 use crate::{
-    Format, Location, Repeat, Replacement, db::LineDb, progress::ProgressState, report::RunReport,
+    Kept, Location, Repeat, RuntimeFormat,
+    lines::{Archive, Record, RecordDelimiter, Source},
+    policy::{CollisionPolicy, Precedence, Utf8Policy},
+    progress::ProgressState,
+    report::{InputStats, RepeatSink, RepeatWriter, RunReport, Timings},
+    store::{
+        Backend, KeyRepeat, LineStore, ManifestEntry, MemoryStore, MergeSortStore, Position,
+        Sources,
+    },
 };
 use futures::{StreamExt, TryFutureExt, TryStreamExt};
+use std::collections::{BTreeMap, HashMap, HashSet, btree_map};
+use std::fs::File;
+use std::io::BufWriter;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
 use tokio::task::JoinHandle;
 
+#[cfg(feature = "rocksdb")]
 const TEMP_DIR_PREFIX: &str = "lines-db";
+const DEFAULT_PARALLELISM: usize = 8;
+
+/// The low bits of a file index, which give the index of an archive member within its archive
+const MEMBER_INDEX_BITS: u32 = usize::BITS / 2;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum FileOrder {
     ByName,
     BySizeInterspersed,
+    /// The order in which the inputs were given (directory contents are still sorted by name)
+    AsGiven,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -19,125 +41,1232 @@ pub enum Error<F> {
     Io(#[from] std::io::Error),
     #[error("Task error")]
     Task(#[from] tokio::task::JoinError),
-    #[error("Database error")]
-    Db(#[from] crate::db::Error<F>),
+    #[error("Store error")]
+    Store(#[from] crate::store::Error<F>),
     #[error("Key parsing error")]
-    KeyParsing(crate::db::Error<F>, PathBuf, usize),
+    KeyParsing(crate::store::Error<F>, PathBuf, usize),
+    #[error("Header error")]
+    Header(F, PathBuf),
     #[error("Input lines error")]
     Lines(#[from] crate::lines::Error),
+    #[error("Missing input paths")]
+    MissingInputs,
+    #[error("Input path does not exist")]
+    InvalidInput(PathBuf),
+    #[error("Missing output directory path")]
+    MissingOutput,
     #[error("Invalid output directory path")]
     InvalidOutput(PathBuf),
+    #[error("Invalid temporary directory path")]
+    InvalidTempBase(PathBuf),
+    #[error("Parallelism must be at least one")]
+    InvalidParallelism,
+    #[error("Invalid ZSTD compression level")]
+    InvalidCompressionLevel(u8),
+    #[error("Database options require the RocksDB backend")]
+    UnsupportedDbOptions,
+    #[cfg(feature = "rocksdb")]
+    #[error("Database memory budget must be larger than the block cache")]
+    InvalidMemoryBudget,
+    #[error("Standard input given more than once")]
+    DuplicateStdin,
+    #[error("Collision history is not supported by the merge sort backend")]
+    UnsupportedHistory,
+    #[error("Input changed since it was read into the database")]
+    ChangedInput(Source),
+    #[error("Inputs added, removed or reordered since an earlier session read into the database")]
+    ChangedInputSet(Source),
+    #[error("Key collision")]
+    Collision {
+        location: Location,
+        old_value: Vec<u8>,
+        new_value: Vec<u8>,
+    },
 }
 
-pub async fn run<
-    F: Format + Clone + Send + 'static,
-    I: AsRef<Path>,
-    O: AsRef<Path>,
-    T: AsRef<Path>,
->(
-    input: I,
-    output: O,
-    temp_base: T,
+/// How a session reports its progress
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Progress {
+    #[default]
+    Hidden,
+    /// Progress bars for reading and writing on standard error
+    Bars,
+}
+
+/// The compression of the output files
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OutputCodec {
+    #[default]
+    Plain,
+    /// ZSTD at the given compression level
+    Zstd(u8),
+}
+
+impl OutputCodec {
+    fn zstd_level(self) -> Option<u8> {
+        match self {
+            Self::Plain => None,
+            Self::Zstd(level) => Some(level),
+        }
+    }
+}
+
+/// Reads the inputs into a store and writes the de-duplicated lines to the output directory
+///
+/// With a RocksDB backend whose `database` is given, the database is kept there instead of in a
+/// temporary directory under the temporary base path. Inputs that were fully read into it by an
+/// earlier session are skipped (and must not have changed since), so a session can be resumed
+/// after an interruption. Inputs may be appended, but not otherwise added, removed or reordered. Only the repeats found by this session are reported, and an input that
+/// was partially read may report some repeats again. Standard input is always read.
+///
+/// Repeats are sent to the repeats sink as they are found (so they are not in input order), and
+/// the report only counts them.
+///
+/// In update mode the existing contents of the output directory are treated as a sorted base
+/// that precedes the inputs (so [`Precedence::Input`] is always used). The base files at the
+/// output paths of new lines are merged with them, repeats against the base are reported, and only
+/// the output paths whose contents change are rewritten. The output codec should match the one
+/// used to write the base.
+///
+/// Stores that stage lines (the merge sort backend and the bulk RocksDB engine) always resolve
+/// repeated keys in input order.
+///
+/// With provenance, a sidecar giving the input location of each line is written next to each
+/// output file, and a RocksDB backend records the locations so that they remain valid when a
+/// persistent database is reused. In update mode, kept lines from the base are located in the
+/// base files.
+///
+/// With a collisions path, the store keeps every line inserted for each key, and the keys with
+/// more than one distinct line are written there (see [`LineStore::write_collisions`]). This is
+/// not supported by the merge sort backend.
+pub struct Session<F> {
+    format: F,
+    inputs: Vec<PathBuf>,
+    output: PathBuf,
+    temp_base: PathBuf,
+    backend: Backend,
     file_order: FileOrder,
     parallelism: usize,
-    compression: Option<u8>,
-    progress_bars: bool,
-) -> Result<RunReport, Error<F::Error>>
+    codec: OutputCodec,
+    collision_policy: CollisionPolicy,
+    precedence: Precedence,
+    utf8_policy: Utf8Policy,
+    record_delimiter: Option<RecordDelimiter>,
+    update: bool,
+    provenance: bool,
+    collisions: Option<PathBuf>,
+    repeats: RepeatSink,
+    progress: Progress,
+}
+
+/// Options for a [`Session`], which are validated by [`SessionBuilder::build`]
+pub struct SessionBuilder<F> {
+    format: F,
+    inputs: Vec<PathBuf>,
+    output: Option<PathBuf>,
+    temp_base: PathBuf,
+    backend: Backend,
+    #[cfg(feature = "rocksdb")]
+    database: Option<PathBuf>,
+    #[cfg(feature = "rocksdb")]
+    engine: Option<crate::db::Engine>,
+    #[cfg(feature = "rocksdb")]
+    db_options: Option<crate::db::DbOptions>,
+    file_order: FileOrder,
+    parallelism: usize,
+    codec: OutputCodec,
+    collision_policy: CollisionPolicy,
+    precedence: Precedence,
+    utf8_policy: Utf8Policy,
+    record_delimiter: Option<RecordDelimiter>,
+    update: bool,
+    provenance: bool,
+    collisions: Option<PathBuf>,
+    repeats: RepeatSink,
+    progress: Progress,
+}
+
+impl<F> SessionBuilder<F> {
+    /// Add an input file or directory path (or `-` for standard input)
+    pub fn with_input<P: AsRef<Path>>(mut self, input: P) -> Self {
+        self.inputs.push(input.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn with_inputs<I: IntoIterator>(mut self, inputs: I) -> Self
+    where
+        I::Item: AsRef<Path>,
+    {
+        self.inputs
+            .extend(inputs.into_iter().map(|input| input.as_ref().to_path_buf()));
+        self
+    }
+
+    /// The output directory, which must exist
+    pub fn with_output<P: AsRef<Path>>(self, output: P) -> Self {
+        Self {
+            output: Some(output.as_ref().to_path_buf()),
+            ..self
+        }
+    }
+
+    /// The directory under which temporary files and databases are created (by default the
+    /// system's temporary directory)
+    pub fn with_temp_base<P: AsRef<Path>>(self, temp_base: P) -> Self {
+        Self {
+            temp_base: temp_base.as_ref().to_path_buf(),
+            ..self
+        }
+    }
+
+    pub fn with_backend(self, backend: Backend) -> Self {
+        Self { backend, ..self }
+    }
+
+    /// A persistent directory for the RocksDB database (which must be selected), so that an
+    /// interrupted session can be resumed
+    #[cfg(feature = "rocksdb")]
+    pub fn with_database<P: AsRef<Path>>(self, database: P) -> Self {
+        Self {
+            database: Some(database.as_ref().to_path_buf()),
+            ..self
+        }
+    }
+
+    /// How lines are loaded into the RocksDB database (which must be selected unless the engine
+    /// is the default)
+    #[cfg(feature = "rocksdb")]
+    pub fn with_engine(self, engine: crate::db::Engine) -> Self {
+        Self {
+            engine: Some(engine),
+            ..self
+        }
+    }
+
+    /// Options for the RocksDB backend (which must be selected)
+    #[cfg(feature = "rocksdb")]
+    pub fn with_db_options(self, db_options: crate::db::DbOptions) -> Self {
+        Self {
+            db_options: Some(db_options),
+            ..self
+        }
+    }
+
+    pub fn with_file_order(self, file_order: FileOrder) -> Self {
+        Self { file_order, ..self }
+    }
+
+    /// The number of inputs read concurrently
+    pub fn with_parallelism(self, parallelism: usize) -> Self {
+        Self {
+            parallelism,
+            ..self
+        }
+    }
+
+    pub fn with_codec(self, codec: OutputCodec) -> Self {
+        Self { codec, ..self }
+    }
+
+    pub fn with_collision_policy(self, collision_policy: CollisionPolicy) -> Self {
+        Self {
+            collision_policy,
+            ..self
+        }
+    }
+
+    pub fn with_precedence(self, precedence: Precedence) -> Self {
+        Self { precedence, ..self }
+    }
+
+    pub fn with_utf8_policy(self, utf8_policy: Utf8Policy) -> Self {
+        Self {
+            utf8_policy,
+            ..self
+        }
+    }
+
+    /// The record delimiter (by default the format's delimiter)
+    pub fn with_record_delimiter(self, record_delimiter: RecordDelimiter) -> Self {
+        Self {
+            record_delimiter: Some(record_delimiter),
+            ..self
+        }
+    }
+
+    /// Merge the inputs into the existing contents of the output directory
+    pub fn with_update(self, update: bool) -> Self {
+        Self { update, ..self }
+    }
+
+    /// Write the input location of each output line to a sidecar file
+    pub fn with_provenance(self, provenance: bool) -> Self {
+        Self { provenance, ..self }
+    }
+
+    /// Write every distinct line of each colliding key to a JSON lines file
+    pub fn with_collisions<P: AsRef<Path>>(self, collisions: P) -> Self {
+        Self {
+            collisions: Some(collisions.as_ref().to_path_buf()),
+            ..self
+        }
+    }
+
+    pub fn with_repeats(self, repeats: RepeatSink) -> Self {
+        Self { repeats, ..self }
+    }
+
+    pub fn with_progress(self, progress: Progress) -> Self {
+        Self { progress, ..self }
+    }
+}
+
+impl<F: RuntimeFormat> SessionBuilder<F> {
+    /// Check the options and build the session
+    pub fn build(self) -> Result<Session<F>, Error<F::Error>> {
+        if self.inputs.is_empty() {
+            return Err(Error::MissingInputs);
+        }
+
+        if let Some(input) = self
+            .inputs
+            .iter()
+            .find(|input| !crate::lines::is_stdin(input) && !input.exists())
+        {
+            return Err(Error::InvalidInput(input.clone()));
+        }
+
+        let output = self.output.ok_or(Error::MissingOutput)?;
+
+        if !output.is_dir() {
+            return Err(Error::InvalidOutput(output));
+        }
+
+        if !self.temp_base.is_dir() {
+            return Err(Error::InvalidTempBase(self.temp_base));
+        }
+
+        if self.parallelism == 0 {
+            return Err(Error::InvalidParallelism);
+        }
+
+        if let OutputCodec::Zstd(level) = self.codec
+            && !zstd::compression_level_range().contains(&i32::from(level))
+        {
+            return Err(Error::InvalidCompressionLevel(level));
+        }
+
+        if self
+            .inputs
+            .iter()
+            .filter(|input| crate::lines::is_stdin(input))
+            .count()
+            > 1
+        {
+            return Err(Error::DuplicateStdin);
+        }
+
+        if self.collisions.is_some() && self.backend == Backend::MergeSort {
+            return Err(Error::UnsupportedHistory);
+        }
+
+        #[cfg(feature = "rocksdb")]
+        let backend = match self.backend {
+            Backend::RocksDb {
+                database,
+                engine,
+                options,
+            } => Backend::RocksDb {
+                database: self.database.or(database),
+                engine: self.engine.unwrap_or(engine),
+                options: self.db_options.unwrap_or(options),
+            },
+            _ if self.database.is_some()
+                || self.db_options.is_some()
+                || self
+                    .engine
+                    .is_some_and(|engine| engine != crate::db::Engine::default()) =>
+            {
+                return Err(Error::UnsupportedDbOptions);
+            }
+            backend => backend,
+        };
+
+        #[cfg(feature = "rocksdb")]
+        if let Backend::RocksDb { options, .. } = &backend
+            && options.exhausts_memory_budget()
+        {
+            return Err(Error::InvalidMemoryBudget);
+        }
+
+        #[cfg(not(feature = "rocksdb"))]
+        let backend = self.backend;
+
+        Ok(Session {
+            format: self.format,
+            inputs: self.inputs,
+            output,
+            temp_base: self.temp_base,
+            backend,
+            file_order: self.file_order,
+            parallelism: self.parallelism,
+            codec: self.codec,
+            collision_policy: self.collision_policy,
+            precedence: self.precedence,
+            utf8_policy: self.utf8_policy,
+            record_delimiter: self.record_delimiter,
+            update: self.update,
+            provenance: self.provenance,
+            collisions: self.collisions,
+            repeats: self.repeats,
+            progress: self.progress,
+        })
+    }
+}
+
+impl<F> Session<F> {
+    pub fn builder(format: F) -> SessionBuilder<F> {
+        SessionBuilder {
+            format,
+            inputs: vec![],
+            output: None,
+            temp_base: std::env::temp_dir(),
+            backend: Backend::default(),
+            #[cfg(feature = "rocksdb")]
+            database: None,
+            #[cfg(feature = "rocksdb")]
+            engine: None,
+            #[cfg(feature = "rocksdb")]
+            db_options: None,
+            file_order: FileOrder::ByName,
+            parallelism: DEFAULT_PARALLELISM,
+            codec: OutputCodec::default(),
+            collision_policy: CollisionPolicy::default(),
+            precedence: Precedence::default(),
+            utf8_policy: Utf8Policy::default(),
+            record_delimiter: None,
+            update: false,
+            provenance: false,
+            collisions: None,
+            repeats: RepeatSink::default(),
+            progress: Progress::default(),
+        }
+    }
+}
+
+impl<F: RuntimeFormat + Clone + Send + 'static> Session<F>
 where
     F::Error: Send,
 {
-    if output.as_ref().is_dir() {
-        let paths = if input.as_ref().is_dir() {
-            let mut paths = file_paths::<F, I>(input, F::is_input_recursive())?;
-            sort_paths(&mut paths, file_order)?;
-            paths
-        } else {
-            vec![input.as_ref().to_path_buf()]
-        };
+    pub async fn run(self) -> Result<RunReport, Error<F::Error>> {
+        let started = Instant::now();
+        let Self {
+            format,
+            inputs,
+            output,
+            temp_base,
+            backend,
+            file_order,
+            parallelism,
+            codec,
+            collision_policy,
+            precedence,
+            utf8_policy,
+            record_delimiter,
+            update,
+            provenance,
+            collisions,
+            repeats,
+            progress,
+        } = self;
 
-        let db_dir = tempdir::TempDir::new_in(temp_base, TEMP_DIR_PREFIX)?;
-        let db = LineDb::<F>::open(db_dir.path())?;
+        let output = output.as_path();
+        let collisions = collisions.as_deref();
+        let compression = codec.zstd_level();
+        let progress_bars = progress == Progress::Bars;
 
-        let mut progress_state = if progress_bars {
-            ProgressState::new()
+        let paths = input_paths(&format, inputs, file_order)?;
+
+        let mut base_files = vec![];
+        if update {
+            file_paths_rec(
+                &|path| crate::store::is_output_path(path),
+                output,
+                true,
+                &mut base_files,
+            )?;
+            base_files.sort();
+        }
+
+        let base = base_files
+            .iter()
+            .enumerate()
+            .filter_map(|(input_index, path)| {
+                let path = path.strip_prefix(output).ok()?;
+                Some((path.to_path_buf(), file_index(input_index, 0)))
+            })
+            .collect::<HashMap<_, _>>();
+        let (sources, tasks) = input_tasks(&base_files, paths);
+        let sources = Arc::new(sources);
+
+        let precedence = if update {
+            Precedence::Input
         } else {
-            ProgressState::default()
+            precedence
         };
 
-        progress_state.init_read_bar(|| paths.len());
-
-        let repeats = futures::stream::iter(paths.into_iter())
-            .map(|path| {
-                let db = db.clone();
-                let progress_bar = progress_state.read_bar();
-                let action: JoinHandle<Result<_, Error<F::Error>>> = tokio::spawn(async move {
-                    let lines = crate::lines::lines(&path)?;
-                    let mut repeats = vec![];
-
-                    for result in lines {
-                        let (line_number, line) = result?;
-                        if let Some(replaced) = db
-                            .insert(&line)
-                            .map_err(|error| Error::KeyParsing(error, path.clone(), line_number))?
-                        {
-                            let replacement = replaced.map(|value| Replacement {
-                                old_value: value,
-                                new_value: line.clone(),
-                            });
-
-                            repeats.push(Repeat {
-                                location: Location::new(&path, line_number),
-                                replacement,
-                            });
-                        }
+        let settings = Settings {
+            output,
+            update,
+            provenance,
+            collisions,
+            repeats,
+            parallelism,
+            compression,
+            record_delimiter,
+            utf8_policy,
+            progress_bars,
+            started,
+        };
+        let history = collisions.is_some().then(|| sources.clone());
+
+        match backend {
+            #[cfg(feature = "rocksdb")]
+            Backend::RocksDb {
+                database,
+                engine,
+                options,
+            } => {
+                let temp_dir;
+                let db_path = match database.as_deref() {
+                    Some(path) => path,
+                    None => {
+                        temp_dir = tempdir::TempDir::new_in(&temp_base, TEMP_DIR_PREFIX)?;
+                        temp_dir.path()
                     }
+                };
+
+                let mut store = LineDb::open(
+                    format,
+                    db_path,
+                    &temp_base,
+                    engine,
+                    collision_policy,
+                    precedence,
+                    &options,
+                )?;
+
+                if provenance {
+                    store = store.with_provenance(sources.clone());
+                }
+
+                if let Some(history) = history {
+                    store = store.with_history(history);
+                }
+
+                run_store(store, sources, tasks, base, settings).await
+            }
+            Backend::MergeSort => {
+                let store = MergeSortStore::new(format, temp_base, collision_policy, precedence)?;
+
+                run_store(store, sources, tasks, base, settings).await
+            }
+            Backend::Memory => {
+                let mut store = MemoryStore::new(format, collision_policy, precedence);
+
+                if let Some(history) = history {
+                    store = store.with_history(history);
+                }
+
+                run_store(store, sources, tasks, base, settings).await
+            }
+        }
+    }
+}
+
+/// Session settings that do not depend on the store
+struct Settings<'a> {
+    output: &'a Path,
+    update: bool,
+    provenance: bool,
+    collisions: Option<&'a Path>,
+    repeats: RepeatSink,
+    parallelism: usize,
+    compression: Option<u8>,
+    record_delimiter: Option<RecordDelimiter>,
+    utf8_policy: Utf8Policy,
+    progress_bars: bool,
+    started: Instant,
+}
+
+async fn run_store<F, S>(
+    db: S,
+    sources: Arc<Sources>,
+    tasks: Vec<InputTask>,
+    base: HashMap<PathBuf, usize>,
+    settings: Settings<'_>,
+) -> Result<RunReport, Error<F::Error>>
+where
+    F: RuntimeFormat + Send + 'static,
+    F::Error: Send,
+    S: LineStore<F> + Clone + Send + 'static,
+{
+    let Settings {
+        output,
+        update,
+        provenance,
+        collisions,
+        repeats,
+        parallelism,
+        compression,
+        record_delimiter,
+        utf8_policy,
+        progress_bars,
+        started,
+    } = settings;
+
+    let repeats = Arc::new(RepeatWriter::new(repeats)?);
+
+    // The lines of archive members read by an earlier session may be found before the members
+    for (file_index, source) in db.recorded_sources()? {
+        sources.insert_missing(file_index, source);
+    }
+
+    let delimiter = record_delimiter.unwrap_or_else(|| db.format().record_delimiter());
+    let tasks = pending_tasks(&db, tasks, delimiter)?;
+
+    let mut progress_state = if progress_bars {
+        ProgressState::new()
+    } else {
+        ProgressState::default()
+    };
+
+    progress_state.init_read_bar(|| tasks.len());
 
-                    if let Some(progress_bar) = progress_bar.as_ref() {
-                        progress_bar.inc(1);
+    let task_results: Vec<Ingested> = futures::stream::iter(tasks)
+        .map(|task| {
+            let db = db.clone();
+            let sources = sources.clone();
+            let mut ingested = Ingested::new(repeats.clone(), sources.clone());
+            let progress_bar = progress_state.read_bar();
+            let action: JoinHandle<Result<_, Error<F::Error>>> = tokio::spawn(async move {
+                match task {
+                    InputTask::File {
+                        path,
+                        file_index,
+                        entry,
+                    } => {
+                        insert_records(
+                            &db,
+                            &Source::file(&path),
+                            file_index,
+                            entry.as_ref(),
+                            crate::lines::records(&path, delimiter)?,
+                            utf8_policy,
+                            &mut ingested,
+                        )?;
                     }
+                    InputTask::Archive {
+                        path,
+                        archive,
+                        input_index,
+                    } => {
+                        insert_archive_records(
+                            &db,
+                            &sources,
+                            &path,
+                            archive,
+                            input_index,
+                            delimiter,
+                            utf8_policy,
+                            &mut ingested,
+                        )?;
+                    }
+                }
 
-                    Ok(repeats)
-                });
+                if let Some(progress_bar) = progress_bar.as_ref() {
+                    progress_bar.inc(1);
+                }
 
-                Ok(action.map_ok_or_else(|error| Err(Error::from(error)), |result| result))
-            })
-            .try_buffer_unordered(parallelism)
-            .map_ok(|values| {
-                futures::stream::iter(values).map(|value| {
-                    let result: Result<Repeat, Error<F::Error>> = Ok(value);
-                    result
-                })
-            })
-            .try_flatten()
-            .try_collect()
-            .await?;
+                db.flush()?;
+
+                Ok(ingested)
+            });
 
-        progress_state.finish_read_bar();
-        let write_bar = progress_state.init_write_bar(|| db.count());
+            Ok(action.map_ok_or_else(|error| Err(Error::from(error)), |result| result))
+        })
+        .try_buffer_unordered(parallelism)
+        .try_collect()
+        .await?;
+
+    progress_state.finish_read_bar();
+
+    let mut ingested = Ingested::new(repeats.clone(), sources.clone());
+
+    for task_ingested in task_results {
+        ingested.absorb(task_ingested);
+    }
+
+    db.finish(&mut |repeat| ingested.repeat(repeat))
+        .map_err(|error| store_error(&sources, error))?;
+
+    let changed = if update {
+        ingested = ingested.counting_unchanged();
+
+        Some(merge_base(
+            &db,
+            &base,
+            &sources,
+            compression,
+            delimiter,
+            utf8_policy,
+            &mut ingested,
+        )?)
+    } else {
+        None
+    };
+
+    let Ingested { skipped, stats, .. } = ingested;
+    let read = started.elapsed();
+    let write_bar = progress_state.init_write_bar(|| db.count());
 
-        let write_report = db.write(output, compression, write_bar)?;
+    let write_report = db.write(
+        output,
+        compression,
+        delimiter,
+        changed.as_ref(),
+        provenance.then_some(&*sources),
+        write_bar,
+    )?;
 
-        progress_state.finish_write_bar();
+    progress_state.finish_write_bar();
+
+    if let Some(path) = collisions {
+        db.write_collisions(BufWriter::new(File::create(path)?))?;
+    }
+
+    let (duplicates, collisions, repeats_path) = repeats.finish()?;
+    let total = started.elapsed();
+
+    Ok(RunReport {
+        duplicates,
+        collisions,
+        repeats_path,
+        skipped,
+        write_report,
+        timings: Timings {
+            read,
+            write: total - read,
+            total,
+        },
+        inputs: stats.into_values().map(InputStats::finish).collect(),
+    })
+}
+
+/// A unit of reading work: a single input, or every included member of an archive
+enum InputTask {
+    File {
+        path: PathBuf,
+        file_index: usize,
+        /// The current state of the file, once it is known not to have been read already
+        entry: Option<ManifestEntry>,
+    },
+    /// An archive, whose members are given file indices as they are read
+    Archive {
+        path: PathBuf,
+        archive: Archive,
+        input_index: usize,
+    },
+}
+
+/// The file index of a member of an input (or of the input itself, with a member index of zero)
+fn file_index(input_index: usize, member_index: usize) -> usize {
+    (input_index << MEMBER_INDEX_BITS) | member_index
+}
 
-        Ok(RunReport {
+/// Sends the repeats found while reading inputs to the session's sink, and counts skipped lines
+/// and statistics
+struct Ingested {
+    repeats: Arc<RepeatWriter>,
+    sources: Arc<Sources>,
+    skipped: usize,
+    stats: BTreeMap<usize, InputStats>,
+    /// The number of repeats that keep the line at their first occurrence, by its file index
+    unchanged: Option<HashMap<usize, usize>>,
+}
+
+impl Ingested {
+    fn new(repeats: Arc<RepeatWriter>, sources: Arc<Sources>) -> Self {
+        Self {
             repeats,
-            write_report,
+            sources,
+            skipped: 0,
+            stats: BTreeMap::new(),
+            unchanged: None,
+        }
+    }
+
+    /// The statistics of a source, which must be known
+    fn stats(&mut self, file_index: usize) -> Result<&mut InputStats, std::io::Error> {
+        Ok(match self.stats.entry(file_index) {
+            btree_map::Entry::Occupied(entry) => entry.into_mut(),
+            btree_map::Entry::Vacant(entry) => {
+                let source = self
+                    .sources
+                    .get(file_index)
+                    .ok_or_else(|| unknown_source(file_index))?;
+
+                entry.insert(InputStats::new(&source))
+            }
+        })
+    }
+
+    /// Add the skipped lines and statistics counted by another task
+    fn absorb(&mut self, other: Self) {
+        self.skipped += other.skipped;
+
+        for (file_index, stats) in other.stats {
+            match self.stats.entry(file_index) {
+                btree_map::Entry::Occupied(mut entry) => entry.get_mut().add(&stats),
+                btree_map::Entry::Vacant(entry) => {
+                    entry.insert(stats);
+                }
+            }
+        }
+    }
+
+    fn counting_unchanged(self) -> Self {
+        Self {
+            unchanged: Some(HashMap::new()),
+            ..self
+        }
+    }
+
+    fn repeat(&mut self, repeat: KeyRepeat) -> Result<(), std::io::Error> {
+        if let Some(unchanged) = self.unchanged.as_mut()
+            && repeat
+                .replacement
+                .as_ref()
+                .is_none_or(|replacement| replacement.kept == Kept::Old)
+        {
+            *unchanged.entry(repeat.first.file_index).or_insert(0) += 1;
+        }
+
+        let stats = self.stats(repeat.position.file_index)?;
+        if repeat.replacement.is_some() {
+            stats.collisions += 1;
+        } else {
+            stats.duplicates += 1;
+        }
+
+        let location = |position: Position| {
+            self.sources
+                .location(position)
+                .ok_or_else(|| unknown_source(position.file_index))
+        };
+
+        self.repeats.send(Repeat {
+            location: location(repeat.position)?,
+            first: location(repeat.first)?,
+            replacement: repeat.replacement,
         })
-    } else {
-        Err(Error::InvalidOutput(output.as_ref().to_path_buf()))
     }
 }
 
-fn file_paths<F: Format, P: AsRef<Path>>(
-    base: P,
-    recursive: bool,
-) -> Result<Vec<PathBuf>, std::io::Error> {
+fn unknown_source(file_index: usize) -> std::io::Error {
+    std::io::Error::other(format!("Unknown source for file index {file_index}"))
+}
+
+/// Convert a store error, giving a collision the location of the repeated line
+fn store_error<E>(sources: &Sources, error: crate::store::Error<E>) -> Error<E> {
+    match error {
+        crate::store::Error::Collision {
+            position,
+            old_value,
+            new_value,
+        } => match sources.location(position) {
+            Some(location) => Error::Collision {
+                location,
+                old_value,
+                new_value,
+            },
+            None => unknown_source(position.file_index).into(),
+        },
+        error => error.into(),
+    }
+}
+
+/// The sources indexed by file index, and the tasks that read them
+type InputTasks = (Sources, Vec<InputTask>);
+
+/// Give each input a file index and a task that reads it
+///
+/// The base files come first, and are not read by any task. Archive members are only given
+/// sources when they are read.
+fn input_tasks(base_files: &[PathBuf], paths: Vec<PathBuf>) -> InputTasks {
+    let sources = Sources::default();
+
+    for (input_index, path) in base_files.iter().enumerate() {
+        sources.insert(file_index(input_index, 0), Source::file(path));
+    }
+
+    let tasks = paths
+        .into_iter()
+        .enumerate()
+        .map(|(index, path)| {
+            let input_index = base_files.len() + index;
+
+            match Archive::from_path(&path) {
+                Some(archive) => InputTask::Archive {
+                    path,
+                    archive,
+                    input_index,
+                },
+                None => {
+                    let file_index = file_index(input_index, 0);
+                    sources.insert(file_index, Source::file(&path));
+
+                    InputTask::File {
+                        path,
+                        file_index,
+                        entry: None,
+                    }
+                }
+            }
+        })
+        .collect();
+
+    (sources, tasks)
+}
+
+/// Remove the files that were fully read by an earlier session from the tasks, recording the
+/// current state of the others (except standard input)
+///
+/// Archive members are checked as they are read. If the format has a header, it is read from the
+/// first skipped file, since it may not be read from any other.
+fn pending_tasks<F: RuntimeFormat, S: LineStore<F>>(
+    db: &S,
+    tasks: Vec<InputTask>,
+    delimiter: RecordDelimiter,
+) -> Result<Vec<InputTask>, Error<F::Error>> {
+    let mut pending = Vec::with_capacity(tasks.len());
+    let mut header_read = !db.format().has_header();
+
+    for task in tasks {
+        match task {
+            InputTask::File {
+                path, file_index, ..
+            } if !crate::lines::is_stdin(&path) => {
+                let source = Source::file(&path);
+                let entry = ManifestEntry::for_path(file_index, &path)?;
+
+                if !is_complete(db, &source, &entry)? {
+                    pending.push(InputTask::File {
+                        path,
+                        file_index,
+                        entry: Some(entry),
+                    });
+                } else if !header_read {
+                    read_header(db, &source, crate::lines::records(&path, delimiter)?)?;
+                    header_read = true;
+                }
+            }
+            task => pending.push(task),
+        }
+    }
+
+    Ok(pending)
+}
+
+/// Whether an input was fully read by an earlier session, failing if it has changed since
+fn is_complete<F: RuntimeFormat, S: LineStore<F>>(
+    db: &S,
+    source: &Source,
+    entry: &ManifestEntry,
+) -> Result<bool, Error<F::Error>> {
+    match db.manifest_entry(source)? {
+        // Positions in the database refer to sources by index
+        Some(recorded) if recorded.file_index != entry.file_index => {
+            Err(Error::ChangedInputSet(source.clone()))
+        }
+        Some(recorded) if recorded == *entry => Ok(true),
+        Some(_) => Err(Error::ChangedInput(source.clone())),
+        None => Ok(false),
+    }
+}
+
+/// Merge the base files at the output paths of the inserted lines into the store
+///
+/// Returns the output paths whose contents change.
+fn merge_base<F: RuntimeFormat, S: LineStore<F>>(
+    db: &S,
+    base: &HashMap<PathBuf, usize>,
+    sources: &Sources,
+    compression: Option<u8>,
+    delimiter: RecordDelimiter,
+    utf8_policy: Utf8Policy,
+    ingested: &mut Ingested,
+) -> Result<HashSet<PathBuf>, Error<F::Error>> {
+    let mut changed = HashSet::new();
+    let mut merged = HashMap::new();
+
+    for (path, new_count) in db.path_counts()? {
+        match base.get(&crate::store::output_path(&path, compression)) {
+            Some(&file_index) => {
+                let source = sources
+                    .get(file_index)
+                    .ok_or(crate::store::Error::InvalidState)?;
+                let mut records = crate::lines::records(&source.path, delimiter)?;
+
+                // Headers that are written to output files without being read are skipped here
+                if !db.format().has_header() && db.format().header(&path).is_some() {
+                    records.next().transpose()?;
+                }
+
+                insert_records(
+                    db,
+                    &source,
+                    file_index,
+                    None,
+                    records,
+                    utf8_policy,
+                    ingested,
+                )?;
+
+                merged.insert(file_index, (path, new_count));
+            }
+            None => {
+                changed.insert(path);
+            }
+        }
+    }
+
+    db.finish(&mut |repeat| ingested.repeat(repeat))
+        .map_err(|error| store_error(sources, error))?;
+
+    for file_index in merged.keys() {
+        if let Some(stats) = ingested.stats.get_mut(file_index) {
+            stats.base = true;
+        }
+    }
+
+    // Every new key must match a base line that is kept for the file to be unchanged
+    let unchanged_counts = ingested.unchanged.take().unwrap_or_default();
+
+    for (file_index, (path, new_count)) in merged {
+        if unchanged_counts
+            .get(&file_index)
+            .copied()
+            .unwrap_or_default()
+            < new_count
+        {
+            changed.insert(path);
+        }
+    }
+
+    Ok(changed)
+}
+
+/// Read only the header of a source
+fn read_header<
+    F: RuntimeFormat,
+    S: LineStore<F>,
+    R: Iterator<Item = Result<Record, crate::lines::Error>>,
+>(
+    db: &S,
+    source: &Source,
+    mut records: R,
+) -> Result<(), Error<F::Error>> {
+    if let Some(header) = records.next().transpose()? {
+        db.format()
+            .read_header(&String::from_utf8_lossy(&header.bytes))
+            .map_err(|error| Error::Header(error, source.path.clone()))?;
+    }
+
+    Ok(())
+}
+
+/// Insert the records of the included members of an archive, giving each member its source
+///
+/// Only the first member with a given name is read, and members that were fully read by an
+/// earlier session are skipped (except for their headers).
+#[allow(clippy::too_many_arguments)]
+fn insert_archive_records<F: RuntimeFormat, S: LineStore<F>>(
+    db: &S,
+    sources: &Sources,
+    path: &Path,
+    archive: Archive,
+    input_index: usize,
+    delimiter: RecordDelimiter,
+    utf8_policy: Utf8Policy,
+    ingested: &mut Ingested,
+) -> Result<(), Error<F::Error>> {
+    let mut names = HashSet::new();
+
+    crate::lines::archive_records(
+        path,
+        archive,
+        delimiter,
+        |member| db.format().include(Path::new(member)),
+        |member_index, member, records| {
+            if !names.insert(member.to_string()) {
+                return Ok(());
+            }
+
+            let source = Source::member(path, member);
+            let file_index = file_index(input_index, member_index);
+
+            // The source must be known (here and to later sessions) before its lines are
+            sources.insert(file_index, source.clone());
+            db.record_source(file_index, &source)?;
+
+            let entry = ManifestEntry::for_path(file_index, path)?;
+
+            if !is_complete(db, &source, &entry)? {
+                insert_records(
+                    db,
+                    &source,
+                    file_index,
+                    Some(&entry),
+                    records,
+                    utf8_policy,
+                    ingested,
+                )
+            } else if db.format().has_header() {
+                read_header(db, &source, records)
+            } else {
+                Ok(())
+            }
+        },
+    )
+}
+
+/// Insert the records of a single source, collecting repeats and skipped lines
+fn insert_records<
+    F: RuntimeFormat,
+    S: LineStore<F>,
+    R: Iterator<Item = Result<Record, crate::lines::Error>>,
+>(
+    db: &S,
+    source: &Source,
+    file_index: usize,
+    entry: Option<&ManifestEntry>,
+    mut records: R,
+    utf8_policy: Utf8Policy,
+    ingested: &mut Ingested,
+) -> Result<(), Error<F::Error>> {
+    let started = Instant::now();
+    let mut lines = 0;
+    let mut bytes = 0;
+    let mut key_errors = 0;
+
+    if db.format().has_header()
+        && let Some(result) = records.next()
+    {
+        let header = result?;
+        bytes += header.bytes.len() as u64;
+        db.format()
+            .read_header(&String::from_utf8_lossy(&header.bytes))
+            .map_err(|error| Error::Header(error, source.path.clone()))?;
+    }
+
+    for result in records {
+        let Record {
+            line_number,
+            byte_offset,
+            bytes: line,
+        } = result?;
+        let position = Position::new(file_index, line_number, byte_offset);
+
+        lines += 1;
+        bytes += line.len() as u64;
+
+        let inserted = match db.insert(&line, position) {
+            Err(crate::store::Error::Utf8(_)) if utf8_policy == Utf8Policy::Skip => {
+                ingested.skipped += 1;
+                key_errors += 1;
+                continue;
+            }
+            Err(crate::store::Error::Utf8(_)) if utf8_policy == Utf8Policy::Replace => {
+                db.insert(String::from_utf8_lossy(&line).as_bytes(), position)
+            }
+            result => result,
+        };
+
+        if let Some(repeat) = inserted.map_err(|error| match error {
+            crate::store::Error::Collision { .. } => store_error(&ingested.sources, error),
+            error => Error::KeyParsing(error, source.path.clone(), line_number),
+        })? {
+            ingested.repeat(repeat)?;
+        }
+    }
+
+    if let Some(entry) = entry {
+        db.complete(source, entry)?;
+    }
+
+    let stats = ingested.stats(file_index)?;
+    stats.lines += lines;
+    stats.bytes += bytes;
+    stats.key_errors += key_errors;
+    stats.elapsed += started.elapsed();
+
+    Ok(())
+}
+
+/// Expand directories in the inputs and sort the resulting files
+///
+/// Standard input (`-`) may be given at most once, and is always read last.
+fn input_paths<F: RuntimeFormat, I: IntoIterator>(
+    format: &F,
+    inputs: I,
+    file_order: FileOrder,
+) -> Result<Vec<PathBuf>, Error<F::Error>>
+where
+    I::Item: AsRef<Path>,
+{
     let mut result = vec![];
-    file_paths_rec::<F, P>(base, recursive, &mut result)?;
+    let mut stdin = false;
+
+    for input in inputs {
+        let input = input.as_ref();
+
+        if crate::lines::is_stdin(input) {
+            if stdin {
+                return Err(Error::DuplicateStdin);
+            }
+            stdin = true;
+        } else if input.is_dir() {
+            let mut paths = vec![];
+            // Archives are always read, and only their members are filtered
+            file_paths_rec(
+                &|path| Archive::from_path(path).is_some() || format.include(path),
+                input,
+                format.is_input_recursive(),
+                &mut paths,
+            )?;
+
+            if file_order == FileOrder::AsGiven {
+                paths.sort();
+            }
+
+            result.extend(paths);
+        } else {
+            result.push(input.to_path_buf());
+        }
+    }
+
+    sort_paths(&mut result, file_order)?;
+
+    if stdin {
+        result.push(PathBuf::from(crate::lines::STDIN_PATH));
+    }
+
     Ok(result)
 }
 
-fn file_paths_rec<F: Format, P: AsRef<Path>>(
+pub(crate) fn file_paths_rec<P: AsRef<Path>, N: Fn(&Path) -> bool>(
+    include: &N,
     base: P,
     recursive: bool,
     acc: &mut Vec<PathBuf>,
@@ -147,11 +1276,11 @@ fn file_paths_rec<F: Format, P: AsRef<Path>>(
         let path = entry.path();
 
         if path.is_file() {
-            if F::include(&path) {
+            if include(&path) {
                 acc.push(path);
             }
         } else if recursive {
-            file_paths_rec::<F, PathBuf>(path, recursive, acc)?;
+            file_paths_rec(include, path, recursive, acc)?;
         }
     }
 
@@ -163,6 +1292,7 @@ fn sort_paths(paths: &mut Vec<PathBuf>, file_order: FileOrder) -> Result<(), std
         FileOrder::ByName => {
             paths.sort_by_cached_key(|path| path.as_os_str().to_owned());
         }
+        FileOrder::AsGiven => {}
         FileOrder::BySizeInterspersed => {
             let mut with_size_0 = vec![];
 
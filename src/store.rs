@@ -0,0 +1,914 @@
+//! Storage backends that de-duplicate lines by key and iterate over them in key order
+//!
+//! [`MemoryStore`] and [`MergeSortStore`] are always available, and the RocksDB-backed
+//! [`crate::db::LineDb`] requires the `rocksdb` feature.
+
+mod memory;
+mod sort;
+
+pub use memory::MemoryStore;
+pub use sort::MergeSortStore;
+
+#[cfg(feature = "rocksdb")]
+use crate::db::{DbOptions, Engine};
+use crate::{
+    Kept, KeyError, Location, Replacement, RuntimeFormat,
+    lines::{RecordDelimiter, Source},
+    policy::{CollisionPolicy, Precedence},
+    report::WriteReport,
+};
+use chrono::{DateTime, Utc};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+
+pub(crate) const ZSTD_EXTENSION: &str = "zst";
+const PROVENANCE_EXTENSION: &str = "provenance";
+const PARTIAL_EXTENSION: &str = "partial";
+const POSITION_LEN: usize = 24;
+const HEADER_LEN: usize = 2 * POSITION_LEN;
+const CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+/// A key with its kept line and the position of that line
+pub type LineResult<E> = Result<(Box<[u8]>, Vec<u8>, Position), Error<E>>;
+type Combined<E> = Result<(Option<Vec<u8>>, Option<KeyRepeat>), Error<E>>;
+
+/// A key with the distinct lines found for it
+pub type CollisionResult<E> = Result<(Box<[u8]>, Vec<Variant>), Error<E>>;
+
+/// A key, line and input location from a store's collision history, in key order
+pub(crate) type HistoryResult<E> = Result<(Vec<u8>, Vec<u8>, Location), Error<E>>;
+
+/// Receives each repeat found while a store is finished
+pub type RepeatFn<'a> = dyn FnMut(KeyRepeat) -> Result<(), std::io::Error> + 'a;
+
+/// Sorted staged entries (keys from [`staged_key`] and lines)
+pub(crate) type Entries = Vec<(Vec<u8>, Vec<u8>)>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error<F> {
+    #[error("Format error")]
+    Format(F),
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    #[error("UTF-8 decoding error")]
+    Utf8(#[from] std::str::Utf8Error),
+    #[cfg(feature = "rocksdb")]
+    #[error("RocksDB error")]
+    Db(#[from] rocksdb::Error),
+    #[error("Invalid path for key")]
+    InvalidPath(PathBuf, Vec<u8>),
+    #[error("Invalid database state")]
+    InvalidState,
+    #[error("Key collision")]
+    Collision {
+        /// The position of the repeated line
+        position: Position,
+        old_value: Vec<u8>,
+        new_value: Vec<u8>,
+    },
+}
+
+/// The storage backend for a session
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Backend {
+    /// A RocksDB database, which may be kept in a persistent directory to resume a session
+    #[cfg(feature = "rocksdb")]
+    RocksDb {
+        database: Option<PathBuf>,
+        engine: Engine,
+        options: DbOptions,
+    },
+    /// An external merge sort that spills sorted runs to the temporary directory
+    MergeSort,
+    /// An in-memory map
+    Memory,
+}
+
+impl Default for Backend {
+    #[cfg(feature = "rocksdb")]
+    fn default() -> Self {
+        Self::RocksDb {
+            database: None,
+            engine: Engine::default(),
+            options: DbOptions::default(),
+        }
+    }
+
+    #[cfg(not(feature = "rocksdb"))]
+    fn default() -> Self {
+        Self::MergeSort
+    }
+}
+
+impl Backend {
+    #[cfg(feature = "rocksdb")]
+    pub const NAMES: [&'static str; 3] = ["rocksdb", "merge-sort", "memory"];
+    #[cfg(not(feature = "rocksdb"))]
+    pub const NAMES: [&'static str; 2] = ["merge-sort", "memory"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            #[cfg(feature = "rocksdb")]
+            "rocksdb" => Some(Self::default()),
+            "merge-sort" => Some(Self::MergeSort),
+            "memory" => Some(Self::Memory),
+            _ => None,
+        }
+    }
+}
+
+/// The position of a line in the session's sorted list of inputs
+///
+/// File indices are ordered as the inputs are, with the members of an archive in archive order.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Position {
+    pub file_index: usize,
+    pub line_number: usize,
+    pub byte_offset: u64,
+}
+
+impl Position {
+    pub fn new(file_index: usize, line_number: usize, byte_offset: u64) -> Self {
+        Self {
+            file_index,
+            line_number,
+            byte_offset,
+        }
+    }
+
+    fn to_bytes(self) -> [u8; POSITION_LEN] {
+        let mut bytes = [0; POSITION_LEN];
+        bytes[..8].copy_from_slice(&(self.file_index as u64).to_be_bytes());
+        bytes[8..16].copy_from_slice(&(self.line_number as u64).to_be_bytes());
+        bytes[16..].copy_from_slice(&self.byte_offset.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let file_index = u64::from_be_bytes(bytes.get(..8)?.try_into().ok()?);
+        let line_number = u64::from_be_bytes(bytes.get(8..16)?.try_into().ok()?);
+        let byte_offset = u64::from_be_bytes(bytes.get(16..POSITION_LEN)?.try_into().ok()?);
+
+        Some(Self::new(
+            file_index as usize,
+            line_number as usize,
+            byte_offset,
+        ))
+    }
+}
+
+/// The sources of a session by file index
+///
+/// Archive members are added as they are read, since archives are not listed in advance.
+#[derive(Debug, Default)]
+pub struct Sources {
+    sources: RwLock<HashMap<usize, Source>>,
+}
+
+impl Sources {
+    pub fn get(&self, file_index: usize) -> Option<Source> {
+        self.sources
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&file_index)
+            .cloned()
+    }
+
+    /// The input location of a position, if its source is known
+    pub fn location(&self, position: Position) -> Option<Location> {
+        self.sources
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&position.file_index)
+            .map(|source| source.location(position.line_number, position.byte_offset))
+    }
+
+    pub fn insert(&self, file_index: usize, source: Source) {
+        self.sources
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(file_index, source);
+    }
+
+    /// Add a source unless one is already known for its file index
+    pub fn insert_missing(&self, file_index: usize, source: Source) {
+        self.sources
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(file_index)
+            .or_insert(source);
+    }
+}
+
+/// The state of an input at the time it was fully read into a store
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ManifestEntry {
+    pub file_index: usize,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+}
+
+impl ManifestEntry {
+    /// Describe the current state of an input file (or of the archive containing it)
+    pub fn for_path<P: AsRef<Path>>(file_index: usize, path: P) -> Result<Self, std::io::Error> {
+        let metadata = std::fs::metadata(path)?;
+
+        Ok(Self {
+            file_index,
+            size: metadata.len(),
+            modified: metadata.modified()?.into(),
+        })
+    }
+
+    #[cfg_attr(not(feature = "rocksdb"), allow(dead_code))]
+    pub(crate) fn manifest_key(source: &Source) -> Vec<u8> {
+        crate::key::encode(&(
+            source.path.as_os_str().as_encoded_bytes(),
+            source.member.as_deref(),
+        ))
+    }
+
+    #[cfg_attr(not(feature = "rocksdb"), allow(dead_code))]
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        crate::key::encode(&(self.file_index as u64, self.size, self.modified))
+    }
+
+    #[cfg_attr(not(feature = "rocksdb"), allow(dead_code))]
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        let (file_index, size, modified) =
+            crate::key::decode::<(u64, u64, DateTime<Utc>)>(bytes).ok()?;
+
+        Some(Self {
+            file_index: file_index as usize,
+            size,
+            modified,
+        })
+    }
+}
+
+/// A repeated key detected during insertion
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KeyRepeat {
+    /// The position of the later of the two occurrences
+    pub position: Position,
+    /// The position of the earliest occurrence of the key
+    pub first: Position,
+    /// `None` for a duplicate, or the resolved replacement for a collision
+    pub replacement: Option<Replacement>,
+}
+
+/// A distinct line for a key, with the locations at which it was found
+#[derive(Clone, Debug, Eq, PartialEq, serde::Serialize)]
+pub struct Variant {
+    #[serde(serialize_with = "crate::report::serialize_lossy")]
+    pub line: Vec<u8>,
+    pub locations: Vec<Location>,
+}
+
+/// A store that de-duplicates lines by key and iterates over them in key order
+///
+/// Lines may be inserted concurrently from clones of a store.
+pub trait LineStore<F: RuntimeFormat> {
+    fn format(&self) -> &F;
+
+    /// Insert a line, returning the repeat if the key was already found
+    ///
+    /// Stores that stage lines (including every store under [`Precedence::Input`]) only report
+    /// repeats from [`LineStore::finish`].
+    fn insert(&self, line: &[u8], position: Position)
+    -> Result<Option<KeyRepeat>, Error<F::Error>>;
+
+    /// Spill any lines held in memory by this clone of the store
+    ///
+    /// Tasks that insert lines from a clone call this when they are done, so that stores that
+    /// buffer lines for each clone do not keep them in memory until [`LineStore::finish`].
+    fn flush(&self) -> Result<(), Error<F::Error>> {
+        Ok(())
+    }
+
+    /// Finish inserting lines, passing any repeats that have not been reported to `on_repeat` as
+    /// they are found
+    ///
+    /// More lines may be inserted afterwards, but the store must be finished again before it is
+    /// read.
+    fn finish(&self, _on_repeat: &mut RepeatFn<'_>) -> Result<(), Error<F::Error>> {
+        Ok(())
+    }
+
+    /// The number of distinct keys
+    fn count(&self) -> usize;
+
+    /// Iterate over the keys and kept lines (with their positions) in key order
+    fn lines(&self) -> Box<dyn Iterator<Item = LineResult<F::Error>> + '_>;
+
+    /// Look up the manifest entry recorded when an input was fully read (for persistent stores)
+    fn manifest_entry(&self, _source: &Source) -> Result<Option<ManifestEntry>, Error<F::Error>> {
+        Ok(None)
+    }
+
+    /// Record that all lines of an input have been inserted (for persistent stores)
+    fn complete(&self, _source: &Source, _entry: &ManifestEntry) -> Result<(), Error<F::Error>> {
+        Ok(())
+    }
+
+    /// Record the file index of an archive member before its lines are inserted (for persistent
+    /// stores), so that a later session can locate them before it reads the member
+    fn record_source(&self, _file_index: usize, _source: &Source) -> Result<(), Error<F::Error>> {
+        Ok(())
+    }
+
+    /// The archive members recorded by earlier sessions, with their file indices
+    fn recorded_sources(&self) -> Result<Vec<(usize, Source)>, Error<F::Error>> {
+        Ok(vec![])
+    }
+
+    /// Look up the recorded location of the kept line for a key (for stores that record it)
+    ///
+    /// Otherwise the location is found from the kept line's position in the session's sources.
+    fn kept_location(&self, _key: &[u8]) -> Result<Option<Location>, Error<F::Error>> {
+        Ok(None)
+    }
+
+    /// Iterate over the keys with more than one distinct line in key order (for stores that keep a
+    /// collision history)
+    ///
+    /// Variants are listed in the order in which they were first found in the inputs.
+    fn collisions<'a>(&'a self) -> Box<dyn Iterator<Item = CollisionResult<F::Error>> + 'a>
+    where
+        F::Error: 'a,
+    {
+        Box::new(std::iter::empty())
+    }
+
+    /// Write the collision history as JSON lines, returning the number of keys written
+    ///
+    /// Each object gives a key (in hex), its output path, and every variant with its locations.
+    fn write_collisions<W: Write>(&self, mut writer: W) -> Result<usize, Error<F::Error>> {
+        let mut count = 0;
+
+        for result in self.collisions() {
+            let (key, variants) = result?;
+            let path = self.format().path(&key).map_err(Error::Format)?;
+            let hex = key
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect::<String>();
+
+            serde_json::to_writer(
+                &mut writer,
+                &serde_json::json!({
+                    "key": hex,
+                    "path": path.to_string_lossy(),
+                    "variants": variants,
+                }),
+            )
+            .map_err(std::io::Error::from)?;
+            writeln!(writer)?;
+
+            count += 1;
+        }
+
+        writer.flush()?;
+
+        Ok(count)
+    }
+
+    /// Count the distinct keys for each output path
+    fn path_counts(&self) -> Result<HashMap<PathBuf, usize>, Error<F::Error>> {
+        let mut counts = HashMap::new();
+
+        for result in self.lines() {
+            let (key, _, _) = result?;
+            let path = self.format().path(&key).map_err(Error::Format)?;
+            *counts.entry(path).or_default() += 1;
+        }
+
+        Ok(counts)
+    }
+
+    /// Write the lines to the output directory, optionally only those with the given output paths
+    ///
+    /// If the session's sources are given, a provenance sidecar (see [`provenance_path`]) is
+    /// written next to each output file. It has a tab-separated row for each line, giving the
+    /// path, archive member (or an empty string), line number and byte offset of the input line.
+    fn write<P: AsRef<Path>>(
+        &self,
+        base: P,
+        compression: Option<u8>,
+        delimiter: RecordDelimiter,
+        only: Option<&HashSet<PathBuf>>,
+        provenance: Option<&Sources>,
+        progress_bar: Option<indicatif::ProgressBar>,
+    ) -> Result<WriteReport, Error<F::Error>> {
+        let mut file_counts = HashMap::new();
+        let mut last_path = None;
+        let mut writer: Option<PendingOutput> = None;
+        let mut sidecar: Option<PendingOutput> = None;
+
+        for result in self.lines() {
+            let (key, value, position) = result?;
+            let path = self.format().path(&key).map_err(Error::Format)?;
+
+            if only.is_some_and(|only| !only.contains(&path)) {
+                if let Some(progress_bar) = progress_bar.as_ref() {
+                    progress_bar.inc(1);
+                }
+                continue;
+            }
+
+            let count = if Some(&path) != last_path.as_ref() {
+                let entry = file_counts.entry(path.clone());
+                match entry {
+                    Entry::Occupied(_) => Err(Error::InvalidPath(path.clone(), key.to_vec())),
+                    Entry::Vacant(_) => Ok(()),
+                }?;
+
+                finish_outputs(writer.take(), sidecar.take())?;
+
+                writer = Some(PendingOutput::create(
+                    base.as_ref().join(output_path(&path, compression)),
+                    compression,
+                )?);
+
+                if provenance.is_some() {
+                    sidecar = Some(PendingOutput::create(
+                        base.as_ref().join(provenance_path(&path, compression)),
+                        compression,
+                    )?);
+                }
+
+                if let (Some(writer), Some(header)) = (writer.as_mut(), self.format().header(&path))
+                {
+                    writeln!(writer, "{}", header)?;
+                }
+
+                last_path = Some(path);
+                entry
+            } else {
+                let entry = file_counts.entry(path.clone());
+                match entry {
+                    Entry::Occupied(_) => Ok(()),
+                    Entry::Vacant(_) => Err(Error::InvalidState),
+                }?;
+                entry
+            }
+            .or_default();
+
+            match writer {
+                Some(ref mut writer) => {
+                    *count += 1;
+                    writer.write_all(&value)?;
+                    Ok(writer.write_all(delimiter.terminator())?)
+                }
+                None => Err(Error::InvalidState),
+            }?;
+
+            if let (Some(sources), Some(sidecar)) = (provenance, sidecar.as_mut()) {
+                let location = match self.kept_location(&key)? {
+                    Some(location) => location,
+                    None => sources.location(position).ok_or(Error::InvalidState)?,
+                };
+
+                writeln!(
+                    sidecar,
+                    "{}\t{}\t{}\t{}",
+                    location.path.display(),
+                    location.member.as_deref().unwrap_or_default(),
+                    location.line_number,
+                    location.byte_offset
+                )?;
+            }
+
+            if let Some(progress_bar) = progress_bar.as_ref() {
+                progress_bar.inc(1);
+            }
+        }
+
+        finish_outputs(writer, sidecar)?;
+
+        Ok(WriteReport::new(file_counts))
+    }
+}
+
+/// An output file that is written to a temporary path in the same directory, and only replaces
+/// any existing file at its path when it is finished
+struct PendingOutput {
+    writer: Box<dyn Write>,
+    temp_path: PathBuf,
+    path: PathBuf,
+}
+
+impl PendingOutput {
+    fn create(path: PathBuf, compression: Option<u8>) -> Result<Self, std::io::Error> {
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::InvalidFilename))?;
+        let mut temp_name = OsString::from(".");
+        temp_name.push(file_name);
+        temp_name.push(".");
+        temp_name.push(PARTIAL_EXTENSION);
+        let temp_path = path.with_file_name(temp_name);
+
+        Ok(Self {
+            writer: create_output(temp_path.clone(), compression)?,
+            temp_path,
+            path,
+        })
+    }
+
+    fn finish(mut self) -> Result<(), std::io::Error> {
+        self.writer.flush()?;
+        drop(self.writer);
+
+        std::fs::rename(self.temp_path, self.path)
+    }
+}
+
+impl Write for PendingOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn finish_outputs(
+    writer: Option<PendingOutput>,
+    sidecar: Option<PendingOutput>,
+) -> Result<(), std::io::Error> {
+    writer.map(PendingOutput::finish).transpose()?;
+    sidecar.map(PendingOutput::finish).transpose()?;
+
+    Ok(())
+}
+
+/// Create an output file (and any missing parent directories)
+fn create_output(path: PathBuf, compression: Option<u8>) -> Result<Box<dyn Write>, std::io::Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let file = File::create(path)?;
+
+    Ok(match compression {
+        Some(level) => {
+            Box::new(zstd::stream::write::Encoder::new(file, level as i32)?.auto_finish())
+        }
+        None => Box::new(BufWriter::new(file)),
+    })
+}
+
+/// The path relative to the output directory at which the file for an output path is written
+pub fn output_path<P: AsRef<Path>>(path: P, compression: Option<u8>) -> PathBuf {
+    let path = path.as_ref();
+
+    match compression {
+        Some(_) => {
+            let mut new_extension = path.extension().unwrap_or_default().to_os_string();
+            if !new_extension.is_empty() {
+                new_extension.push(".");
+            }
+            new_extension.push(ZSTD_EXTENSION);
+
+            path.with_extension(new_extension)
+        }
+        None => path.to_path_buf(),
+    }
+}
+
+/// The path relative to the output directory of the provenance sidecar for an output path
+pub fn provenance_path<P: AsRef<Path>>(path: P, compression: Option<u8>) -> PathBuf {
+    let mut file_name = path.as_ref().as_os_str().to_os_string();
+    file_name.push(".");
+    file_name.push(PROVENANCE_EXTENSION);
+
+    output_path(file_name, compression)
+}
+
+/// Whether a path in an output directory is a provenance sidecar rather than an output file
+fn is_provenance_path<P: AsRef<Path>>(path: P) -> bool {
+    let path = path.as_ref();
+    let path = if path.extension() == Some(ZSTD_EXTENSION.as_ref()) {
+        path.with_extension("")
+    } else {
+        path.to_path_buf()
+    };
+
+    path.extension() == Some(PROVENANCE_EXTENSION.as_ref())
+}
+
+/// Whether a path in an output directory is an output file, rather than a provenance sidecar or
+/// a partially written file
+pub(crate) fn is_output_path<P: AsRef<Path>>(path: P) -> bool {
+    let path = path.as_ref();
+
+    !is_provenance_path(path) && path.extension() != Some(PARTIAL_EXTENSION.as_ref())
+}
+
+pub(crate) fn line_key<F: RuntimeFormat>(
+    format: &F,
+    line: &[u8],
+) -> Result<Vec<u8>, Error<F::Error>> {
+    format.key_bytes(line).map_err(|error| match error {
+        KeyError::Utf8(error) => Error::Utf8(error),
+        KeyError::Format(error) => Error::Format(error),
+    })
+}
+
+/// Encode a key and position so that staged lines sort by key and then by position
+pub(crate) fn staged_key(key: &[u8], position: Position) -> Vec<u8> {
+    crate::key::encode(&(
+        key,
+        position.file_index as u64,
+        position.line_number as u64,
+        position.byte_offset,
+    ))
+}
+
+pub(crate) fn decode_staged_key(bytes: &[u8]) -> Option<(Vec<u8>, Position)> {
+    let (key, file_index, line_number, byte_offset) =
+        crate::key::decode::<(Vec<u8>, u64, u64, u64)>(bytes).ok()?;
+
+    Some((
+        key,
+        Position::new(file_index as usize, line_number as usize, byte_offset),
+    ))
+}
+
+/// Group history entries by key, keeping the keys with more than one distinct line
+pub(crate) fn group_collisions<'a, E: 'a, I: Iterator<Item = HistoryResult<E>> + 'a>(
+    entries: I,
+) -> Box<dyn Iterator<Item = CollisionResult<E>> + 'a> {
+    let mut entries = entries.peekable();
+
+    Box::new(std::iter::from_fn(move || {
+        loop {
+            let (key, line, location) = match entries.next()? {
+                Ok(entry) => entry,
+                Err(error) => return Some(Err(error)),
+            };
+
+            let mut variants = vec![Variant {
+                line,
+                locations: vec![location],
+            }];
+
+            while let Some(Ok((_, line, location))) =
+                entries.next_if(|entry| matches!(entry, Ok((next_key, _, _)) if *next_key == key))
+            {
+                match variants.iter_mut().find(|variant| variant.line == line) {
+                    Some(variant) => variant.locations.push(location),
+                    None => variants.push(Variant {
+                        line,
+                        locations: vec![location],
+                    }),
+                }
+            }
+
+            if variants.len() > 1 {
+                return Some(Ok((key.into_boxed_slice(), variants)));
+            }
+        }
+    }))
+}
+
+/// A stored value with the position of the first occurrence of its key and of the kept line
+pub(crate) struct StoredValue<'a> {
+    first: Position,
+    pub(crate) kept: Position,
+    pub(crate) line: &'a [u8],
+}
+
+impl<'a> StoredValue<'a> {
+    pub(crate) fn decode(bytes: &'a [u8]) -> Option<Self> {
+        Some(Self {
+            first: Position::from_bytes(bytes.get(..POSITION_LEN)?)?,
+            kept: Position::from_bytes(bytes.get(POSITION_LEN..HEADER_LEN)?)?,
+            line: &bytes[HEADER_LEN..],
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.line.len());
+        bytes.extend_from_slice(&self.first.to_bytes());
+        bytes.extend_from_slice(&self.kept.to_bytes());
+        bytes.extend_from_slice(self.line);
+        bytes
+    }
+}
+
+/// Lines held in memory until they are sorted and spilled
+#[derive(Default)]
+struct Chunk {
+    entries: Entries,
+    size: usize,
+}
+
+impl Chunk {
+    /// Add a line, returning the (unsorted) entries if the chunk is full
+    fn push(&mut self, key: Vec<u8>, line: &[u8]) -> Option<Entries> {
+        self.size += key.len() + line.len();
+        self.entries.push((key, line.to_vec()));
+
+        (self.size >= CHUNK_SIZE).then(|| self.take())
+    }
+
+    /// Remove all entries (unsorted)
+    fn take(&mut self) -> Entries {
+        self.size = 0;
+        std::mem::take(&mut self.entries)
+    }
+}
+
+/// A chunk for each clone of a store, so that concurrent tasks do not share a chunk
+///
+/// Entries are only sorted after they have been taken out of a chunk.
+pub(crate) struct Chunks {
+    local: Arc<Mutex<Chunk>>,
+    /// Every clone's chunk
+    all: Arc<Mutex<Vec<Arc<Mutex<Chunk>>>>>,
+}
+
+impl Default for Chunks {
+    fn default() -> Self {
+        let local = Arc::<Mutex<Chunk>>::default();
+
+        Self {
+            all: Arc::new(Mutex::new(vec![local.clone()])),
+            local,
+        }
+    }
+}
+
+impl Clone for Chunks {
+    fn clone(&self) -> Self {
+        let local = Arc::<Mutex<Chunk>>::default();
+
+        self.all
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(local.clone());
+
+        Self {
+            local,
+            all: self.all.clone(),
+        }
+    }
+}
+
+impl Chunks {
+    /// Add a line to this clone's chunk, returning the sorted entries if the chunk is full
+    pub(crate) fn push<E>(&self, key: Vec<u8>, line: &[u8]) -> Result<Option<Entries>, Error<E>> {
+        let full = self
+            .local
+            .lock()
+            .map_err(|_| Error::InvalidState)?
+            .push(key, line);
+
+        Ok(full.map(sorted))
+    }
+
+    /// Remove and sort the entries in this clone's chunk
+    pub(crate) fn take_local<E>(&self) -> Result<Entries, Error<E>> {
+        let entries = self.local.lock().map_err(|_| Error::InvalidState)?.take();
+
+        Ok(sorted(entries))
+    }
+
+    /// Remove and sort the entries in every clone's chunk, returning the non-empty chunks
+    ///
+    /// The chunks of clones that have been dropped are forgotten.
+    pub(crate) fn take_all<E>(&self) -> Result<Vec<Entries>, Error<E>> {
+        let mut all = self.all.lock().map_err(|_| Error::InvalidState)?;
+        let mut result = vec![];
+
+        for chunk in all.iter() {
+            let entries = chunk.lock().map_err(|_| Error::InvalidState)?.take();
+
+            if !entries.is_empty() {
+                result.push(entries);
+            }
+        }
+
+        all.retain(|chunk| Arc::strong_count(chunk) > 1);
+        drop(all);
+
+        Ok(result.into_iter().map(sorted).collect())
+    }
+}
+
+/// Sort entries by key, and then by line
+fn sorted(mut entries: Entries) -> Entries {
+    entries.sort_unstable();
+    entries
+}
+
+/// Resolves repeated keys according to a collision policy and precedence
+#[derive(Clone, Debug)]
+pub(crate) struct Resolver {
+    policy: CollisionPolicy,
+    precedence: Precedence,
+}
+
+impl Resolver {
+    pub(crate) fn new(policy: CollisionPolicy, precedence: Precedence) -> Self {
+        Self { policy, precedence }
+    }
+
+    /// Combine a line with the stored value for its key, returning the new value if it changed
+    ///
+    /// Under [`Precedence::Input`], stores must combine the lines for a key in input order (by
+    /// staging them until [`LineStore::finish`]), since both the kept value and the
+    /// classification of each repeat depend on the order in which lines are combined.
+    pub(crate) fn combine<E>(
+        &self,
+        value: Option<&[u8]>,
+        line: &[u8],
+        position: Position,
+    ) -> Combined<E> {
+        let Some(bytes) = value else {
+            let stored = StoredValue {
+                first: position,
+                kept: position,
+                line,
+            };
+
+            return Ok((Some(stored.encode()), None));
+        };
+
+        let stored = StoredValue::decode(bytes).ok_or(Error::InvalidState)?;
+
+        // The line was already inserted by an interrupted session
+        if position == stored.first || position == stored.kept {
+            return Ok((None, None));
+        }
+
+        let (first, repeat_position) = self.order(position, stored.first);
+        let (earlier, later) = self.order(position, stored.kept);
+
+        let replacement = if stored.line == line {
+            None
+        } else {
+            let (old_value, new_value) = if earlier == position {
+                (line, stored.line)
+            } else {
+                (stored.line, line)
+            };
+
+            let kept =
+                self.policy
+                    .resolve(old_value, new_value)
+                    .ok_or_else(|| Error::Collision {
+                        position: repeat_position,
+                        old_value: old_value.to_vec(),
+                        new_value: new_value.to_vec(),
+                    })?;
+
+            Some(Replacement {
+                old_value: old_value.to_vec(),
+                new_value: new_value.to_vec(),
+                kept,
+            })
+        };
+
+        // Merged values take the position of the earlier line
+        let (kept, line) = match &replacement {
+            None => (earlier, line),
+            Some(replacement) => (
+                if replacement.kept == Kept::New {
+                    later
+                } else {
+                    earlier
+                },
+                replacement.kept_value(),
+            ),
+        };
+
+        let updated = (first != stored.first || kept != stored.kept || line != stored.line)
+            .then(|| StoredValue { first, kept, line }.encode());
+
+        Ok((
+            updated,
+            Some(KeyRepeat {
+                position: repeat_position,
+                first,
+                replacement,
+            }),
+        ))
+    }
+
+    /// Order an incoming position and a stored position, returning the earlier one first
+    fn order(&self, incoming: Position, stored: Position) -> (Position, Position) {
+        match self.precedence {
+            Precedence::Input if incoming < stored => (incoming, stored),
+            _ => (stored, incoming),
+        }
+    }
+}
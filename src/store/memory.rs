@@ -0,0 +1,221 @@
+use super::{
+    CollisionResult, Error, KeyRepeat, LineResult, LineStore, Position, RepeatFn, Resolver,
+    Sources, StoredValue,
+};
+use crate::{
+    Location, RuntimeFormat,
+    policy::{CollisionPolicy, Precedence},
+};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+
+/// A store that keeps all lines in memory, for small inputs and tests
+///
+/// Under [`Precedence::Input`], lines are staged and only combined (and their repeats reported)
+/// by [`LineStore::finish`], in input order.
+#[derive(Clone)]
+pub struct MemoryStore<F> {
+    lines: Arc<Mutex<Lines>>,
+    /// Lines by staged key, if they are combined in input order
+    staged: Option<Arc<Mutex<Lines>>>,
+    resolver: Resolver,
+    format: F,
+    history: Option<History>,
+}
+
+/// Values (or staged lines) by key
+type Lines = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// The lines of colliding keys with their locations, by key and position
+type HistoryLines = BTreeMap<(Vec<u8>, Position), (Vec<u8>, Location)>;
+
+/// The distinct lines of each colliding key, with the session's sources
+#[derive(Clone)]
+struct History {
+    sources: Arc<Sources>,
+    lines: Arc<Mutex<HistoryLines>>,
+}
+
+impl History {
+    fn location<E>(&self, position: Position) -> Result<Location, Error<E>> {
+        self.sources.location(position).ok_or(Error::InvalidState)
+    }
+}
+
+impl<F: RuntimeFormat> MemoryStore<F> {
+    pub fn new(format: F, policy: CollisionPolicy, precedence: Precedence) -> Self {
+        Self {
+            lines: Arc::default(),
+            staged: (precedence == Precedence::Input).then(Arc::default),
+            resolver: Resolver::new(policy, precedence),
+            format,
+            history: None,
+        }
+    }
+
+    /// Keep every distinct line for each colliding key, given the session's sources
+    pub fn with_history(self, sources: Arc<Sources>) -> Self {
+        Self {
+            history: Some(History {
+                sources,
+                lines: Arc::default(),
+            }),
+            ..self
+        }
+    }
+
+    /// The entry following the given key (or the first entry)
+    fn next_after(&self, key: Option<&[u8]>) -> Option<LineResult<F::Error>> {
+        let lines = match self.lines.lock() {
+            Ok(lines) => lines,
+            Err(_) => return Some(Err(Error::InvalidState)),
+        };
+
+        let lower = key.map_or(Bound::Unbounded, Bound::Excluded);
+        let (key, value) = lines.range::<[u8], _>((lower, Bound::Unbounded)).next()?;
+
+        Some(
+            StoredValue::decode(value)
+                .map(|stored| {
+                    (
+                        key.clone().into_boxed_slice(),
+                        stored.line.to_vec(),
+                        stored.kept,
+                    )
+                })
+                .ok_or(Error::InvalidState),
+        )
+    }
+
+    /// Combine a line with the stored value for its key, recording any collision in the history
+    ///
+    /// A key's history starts at its first collision, with the line that was stored for it.
+    fn combine(
+        &self,
+        lines: &mut Lines,
+        key: Vec<u8>,
+        line: &[u8],
+        position: Position,
+    ) -> Result<Option<KeyRepeat>, Error<F::Error>> {
+        let value = lines.get(&key).map(Vec::as_slice);
+        let (updated, repeat) = self.resolver.combine(value, line, position)?;
+
+        if let Some(history) = self.history.as_ref()
+            && let Some(value) = value
+            && repeat
+                .as_ref()
+                .is_some_and(|repeat| repeat.replacement.is_some())
+        {
+            let stored = StoredValue::decode(value).ok_or(Error::InvalidState)?;
+            let stored_location = history.location(stored.kept)?;
+            let location = history.location(position)?;
+            let mut history_lines = history.lines.lock().map_err(|_| Error::InvalidState)?;
+
+            // A merged value must not replace the line that was found at its position
+            history_lines
+                .entry((key.clone(), stored.kept))
+                .or_insert_with(|| (stored.line.to_vec(), stored_location));
+            history_lines.insert((key.clone(), position), (line.to_vec(), location));
+        }
+
+        if let Some(updated) = updated {
+            lines.insert(key, updated);
+        }
+
+        Ok(repeat)
+    }
+}
+
+impl<F: RuntimeFormat> LineStore<F> for MemoryStore<F> {
+    fn format(&self) -> &F {
+        &self.format
+    }
+
+    fn insert(
+        &self,
+        line: &[u8],
+        position: Position,
+    ) -> Result<Option<KeyRepeat>, Error<F::Error>> {
+        let key = super::line_key(&self.format, line)?;
+
+        if let Some(staged) = self.staged.as_ref() {
+            staged
+                .lock()
+                .map_err(|_| Error::InvalidState)?
+                .insert(super::staged_key(&key, position), line.to_vec());
+
+            return Ok(None);
+        }
+
+        let mut lines = self.lines.lock().map_err(|_| Error::InvalidState)?;
+
+        self.combine(&mut lines, key, line, position)
+    }
+
+    /// Combine the staged lines in input order
+    fn finish(&self, on_repeat: &mut RepeatFn<'_>) -> Result<(), Error<F::Error>> {
+        let Some(staged) = self.staged.as_ref() else {
+            return Ok(());
+        };
+
+        let staged = std::mem::take(&mut *staged.lock().map_err(|_| Error::InvalidState)?);
+        let mut lines = self.lines.lock().map_err(|_| Error::InvalidState)?;
+
+        for (staged_key, line) in staged {
+            let (key, position) =
+                super::decode_staged_key(&staged_key).ok_or(Error::InvalidState)?;
+
+            if let Some(repeat) = self.combine(&mut lines, key, &line, position)? {
+                on_repeat(repeat)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn count(&self) -> usize {
+        self.lines.lock().map_or(0, |lines| lines.len())
+    }
+
+    fn lines(&self) -> Box<dyn Iterator<Item = LineResult<F::Error>> + '_> {
+        let mut last_key: Option<Box<[u8]>> = None;
+        let mut failed = false;
+
+        Box::new(std::iter::from_fn(move || {
+            if failed {
+                return None;
+            }
+
+            let result = self.next_after(last_key.as_deref())?;
+
+            match &result {
+                Ok((key, _, _)) => last_key = Some(key.clone()),
+                Err(_) => failed = true,
+            }
+
+            Some(result)
+        }))
+    }
+
+    fn collisions<'a>(&'a self) -> Box<dyn Iterator<Item = CollisionResult<F::Error>> + 'a>
+    where
+        F::Error: 'a,
+    {
+        let Some(history) = self.history.as_ref() else {
+            return Box::new(std::iter::empty());
+        };
+
+        let entries = match history.lines.lock() {
+            Ok(lines) => lines
+                .iter()
+                .map(|((key, _), (line, location))| {
+                    Ok((key.clone(), line.clone(), location.clone()))
+                })
+                .collect::<Vec<_>>(),
+            Err(_) => vec![Err(Error::InvalidState)],
+        };
+
+        super::group_collisions(entries.into_iter())
+    }
+}
@@ -0,0 +1,291 @@
+use super::{
+    Chunks, Entries, Error, KeyRepeat, LineResult, LineStore, Position, RepeatFn, Resolver,
+    StoredValue,
+};
+use crate::{
+    RuntimeFormat,
+    policy::{CollisionPolicy, Precedence},
+};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+const TEMP_DIR_PREFIX: &str = "lines-sort";
+
+/// A key and value in a run file
+type Entry = (Vec<u8>, Vec<u8>);
+
+/// A store that sorts lines externally, spilling sorted runs to a temporary directory
+///
+/// Repeats are only detected when the runs are merged by [`LineStore::finish`], where keys are
+/// always resolved in input order.
+#[derive(Clone)]
+pub struct MergeSortStore<F> {
+    inner: Arc<Inner>,
+    chunks: Chunks,
+    resolver: Resolver,
+    format: F,
+}
+
+struct Inner {
+    dir: tempdir::TempDir,
+    runs: Mutex<Vec<PathBuf>>,
+    merged: Mutex<Option<Merged>>,
+    file_count: AtomicUsize,
+}
+
+/// A run of unique keys with their stored values
+struct Merged {
+    path: PathBuf,
+    count: usize,
+}
+
+impl<F: RuntimeFormat> MergeSortStore<F> {
+    pub fn new<P: AsRef<Path>>(
+        format: F,
+        temp_base: P,
+        policy: CollisionPolicy,
+        precedence: Precedence,
+    ) -> Result<Self, std::io::Error> {
+        Ok(Self {
+            inner: Arc::new(Inner {
+                dir: tempdir::TempDir::new_in(temp_base, TEMP_DIR_PREFIX)?,
+                runs: Mutex::default(),
+                merged: Mutex::default(),
+                file_count: AtomicUsize::new(0),
+            }),
+            chunks: Chunks::default(),
+            resolver: Resolver::new(policy, precedence),
+            format,
+        })
+    }
+
+    fn next_path(&self) -> PathBuf {
+        let index = self.inner.file_count.fetch_add(1, Ordering::SeqCst);
+        self.inner.dir.path().join(format!("run-{index}"))
+    }
+
+    fn write_run(&self, entries: &Entries) -> Result<(), Error<F::Error>> {
+        let path = self.next_path();
+        let mut writer = BufWriter::new(File::create(&path)?);
+
+        for (key, value) in entries {
+            write_entry(&mut writer, key, value)?;
+        }
+
+        writer.flush()?;
+        self.inner
+            .runs
+            .lock()
+            .map_err(|_| Error::InvalidState)?
+            .push(path);
+
+        Ok(())
+    }
+}
+
+impl<F: RuntimeFormat> LineStore<F> for MergeSortStore<F> {
+    fn format(&self) -> &F {
+        &self.format
+    }
+
+    fn insert(
+        &self,
+        line: &[u8],
+        position: Position,
+    ) -> Result<Option<KeyRepeat>, Error<F::Error>> {
+        let key = super::staged_key(&super::line_key(&self.format, line)?, position);
+
+        if let Some(entries) = self.chunks.push(key, line)? {
+            self.write_run(&entries)?;
+        }
+
+        Ok(None)
+    }
+
+    fn flush(&self) -> Result<(), Error<F::Error>> {
+        let entries = self.chunks.take_local()?;
+
+        if !entries.is_empty() {
+            self.write_run(&entries)?;
+        }
+
+        Ok(())
+    }
+
+    /// Merge the runs (and any previously merged lines) in input order
+    fn finish(&self, on_repeat: &mut RepeatFn<'_>) -> Result<(), Error<F::Error>> {
+        for entries in self.chunks.take_all()? {
+            self.write_run(&entries)?;
+        }
+
+        let runs = std::mem::take(&mut *self.inner.runs.lock().map_err(|_| Error::InvalidState)?);
+        let mut merged = self.inner.merged.lock().map_err(|_| Error::InvalidState)?;
+
+        // Previously merged keys sort before the staged lines with the same key
+        let mut readers = runs
+            .iter()
+            .map(|path| Ok(BufReader::new(File::open(path)?)))
+            .collect::<Result<Vec<_>, std::io::Error>>()?;
+        let merged_index = readers.len();
+
+        if let Some(merged) = merged.as_ref() {
+            readers.push(BufReader::new(File::open(&merged.path)?));
+        }
+
+        let mut heap = BinaryHeap::new();
+        for (index, reader) in readers.iter_mut().enumerate() {
+            if let Some((key, value)) = read_entry(reader)? {
+                heap.push(Reverse((key, index, value)));
+            }
+        }
+
+        let path = self.next_path();
+        let mut writer = BufWriter::new(File::create(&path)?);
+        let mut count = 0;
+        let mut current: Option<(Vec<u8>, Vec<u8>)> = None;
+
+        while let Some(Reverse((sort_key, index, value))) = heap.pop() {
+            if let Some((key, value)) = read_entry(&mut readers[index])? {
+                heap.push(Reverse((key, index, value)));
+            }
+
+            let (key, position) = if index == merged_index {
+                let key =
+                    crate::key::decode::<Vec<u8>>(&sort_key).map_err(|_| Error::InvalidState)?;
+                (key, None)
+            } else {
+                let (key, position) =
+                    super::decode_staged_key(&sort_key).ok_or(Error::InvalidState)?;
+                (key, Some(position))
+            };
+
+            let stored = match current.take() {
+                Some((current_key, stored)) if current_key == key => Some(stored),
+                Some((current_key, stored)) => {
+                    write_entry(&mut writer, &crate::key::encode(&current_key), &stored)?;
+                    count += 1;
+                    None
+                }
+                None => None,
+            };
+
+            let stored = match position {
+                Some(position) => {
+                    let (updated, repeat) =
+                        self.resolver.combine(stored.as_deref(), &value, position)?;
+
+                    if let Some(repeat) = repeat {
+                        on_repeat(repeat)?;
+                    }
+
+                    updated.or(stored).ok_or(Error::InvalidState)?
+                }
+                None => value,
+            };
+
+            current = Some((key, stored));
+        }
+
+        if let Some((key, stored)) = current {
+            write_entry(&mut writer, &crate::key::encode(&key), &stored)?;
+            count += 1;
+        }
+
+        writer.flush()?;
+
+        for run in runs {
+            std::fs::remove_file(run)?;
+        }
+
+        if let Some(previous) = merged.replace(Merged { path, count }) {
+            std::fs::remove_file(previous.path)?;
+        }
+
+        Ok(())
+    }
+
+    fn count(&self) -> usize {
+        self.inner
+            .merged
+            .lock()
+            .ok()
+            .and_then(|merged| merged.as_ref().map(|merged| merged.count))
+            .unwrap_or_default()
+    }
+
+    fn lines(&self) -> Box<dyn Iterator<Item = LineResult<F::Error>> + '_> {
+        let path = match self.inner.merged.lock() {
+            Ok(merged) => merged.as_ref().map(|merged| merged.path.clone()),
+            Err(_) => return Box::new(std::iter::once(Err(Error::InvalidState))),
+        };
+
+        let Some(path) = path else {
+            return Box::new(std::iter::empty());
+        };
+
+        let mut reader = match File::open(path) {
+            Ok(file) => BufReader::new(file),
+            Err(error) => return Box::new(std::iter::once(Err(error.into()))),
+        };
+        let mut failed = false;
+
+        Box::new(std::iter::from_fn(move || {
+            if failed {
+                return None;
+            }
+
+            let result = read_entry(&mut reader)
+                .map_err(Error::from)
+                .and_then(|entry| {
+                    entry
+                        .map(|(sort_key, stored)| {
+                            let key = crate::key::decode::<Vec<u8>>(&sort_key)
+                                .map_err(|_| Error::InvalidState)?;
+                            let stored = StoredValue::decode(&stored).ok_or(Error::InvalidState)?;
+
+                            Ok((key.into_boxed_slice(), stored.line.to_vec(), stored.kept))
+                        })
+                        .transpose()
+                })
+                .transpose()?;
+
+            failed = result.is_err();
+
+            Some(result)
+        }))
+    }
+}
+
+fn write_entry<W: Write>(writer: &mut W, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+    writer.write_all(&(key.len() as u64).to_be_bytes())?;
+    writer.write_all(key)?;
+    writer.write_all(&(value.len() as u64).to_be_bytes())?;
+    writer.write_all(value)
+}
+
+fn read_entry<R: Read>(reader: &mut R) -> Result<Option<Entry>, std::io::Error> {
+    let mut len = [0; 8];
+
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error),
+    }
+
+    let key = read_bytes(reader, u64::from_be_bytes(len))?;
+    reader.read_exact(&mut len)?;
+    let value = read_bytes(reader, u64::from_be_bytes(len))?;
+
+    Ok(Some((key, value)))
+}
+
+fn read_bytes<R: Read>(reader: &mut R, len: u64) -> Result<Vec<u8>, std::io::Error> {
+    let mut bytes = vec![0; len as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
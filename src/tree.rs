@@ -0,0 +1,382 @@
+//! Reading existing output directories
+
+use crate::{
+    Location, RuntimeFormat,
+    lines::{Record, RecordDelimiter},
+    store::ZSTD_EXTENSION,
+};
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+
+/// A record with its key and location
+type Keyed = (Vec<u8>, Vec<u8>, Location);
+type KeyedResult<E> = Result<Keyed, Error<E>>;
+type KeyedRecords<'a, E> = Box<dyn Iterator<Item = KeyedResult<E>> + 'a>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error<F> {
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    #[error("Line reading error")]
+    Lines(#[from] crate::lines::Error),
+    #[error("Format error")]
+    Format(F),
+    #[error("Header error")]
+    Header(F, PathBuf),
+    #[error("Invalid key")]
+    InvalidKey(Location),
+    #[error("Invalid state")]
+    InvalidState,
+}
+
+/// A file in an output directory
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OutputFile {
+    pub path: PathBuf,
+    /// The path relative to the output directory, without any compression extension
+    pub relative: PathBuf,
+    pub compressed: bool,
+}
+
+impl OutputFile {
+    fn new(base: &Path, path: PathBuf) -> Self {
+        let relative = path.strip_prefix(base).unwrap_or(&path).to_path_buf();
+        let compressed = relative.extension() == Some(ZSTD_EXTENSION.as_ref());
+        let relative = if compressed {
+            relative.with_extension("")
+        } else {
+            relative
+        };
+
+        Self {
+            path,
+            relative,
+            compressed,
+        }
+    }
+
+    /// The file's records, after reading any header
+    pub fn records<F: RuntimeFormat>(
+        &self,
+        format: &F,
+        delimiter: RecordDelimiter,
+    ) -> Result<impl Iterator<Item = Result<Record, crate::lines::Error>> + use<F>, Error<F::Error>>
+    {
+        let mut records = crate::lines::records(&self.path, delimiter)?;
+
+        let header = if format.has_header() || format.header(&self.relative).is_some() {
+            records.next().transpose()?
+        } else {
+            None
+        };
+
+        if let Some(header) = header
+            && format.has_header()
+        {
+            format
+                .read_header(&String::from_utf8_lossy(&header.bytes))
+                .map_err(|error| Error::Header(error, self.path.clone()))?;
+        }
+
+        Ok(records)
+    }
+
+    /// The file's records with their keys, failing on any line without a valid key
+    fn keyed<'a, F: RuntimeFormat>(
+        &self,
+        format: &'a F,
+        delimiter: RecordDelimiter,
+    ) -> Result<impl Iterator<Item = KeyedResult<F::Error>> + use<'a, F>, Error<F::Error>> {
+        let path = self.path.clone();
+
+        Ok(self.records(format, delimiter)?.map(move |record| {
+            let record = record?;
+            let location = Location::new(&path, record.line_number, record.byte_offset);
+            let key = crate::store::line_key(format, &record.bytes)
+                .map_err(|_| Error::InvalidKey(location.clone()))?;
+
+            Ok((key, record.bytes, location))
+        }))
+    }
+}
+
+/// List the files in an output directory in path order, ignoring provenance sidecars and partially
+/// written files
+pub fn output_files<P: AsRef<Path>>(base: P) -> Result<Vec<OutputFile>, std::io::Error> {
+    let base = base.as_ref();
+    let mut paths = vec![];
+
+    crate::session::file_paths_rec(
+        &|path| crate::store::is_output_path(path),
+        base,
+        true,
+        &mut paths,
+    )?;
+    paths.sort();
+
+    Ok(paths
+        .into_iter()
+        .map(|path| OutputFile::new(base, path))
+        .collect())
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct FileStats {
+    #[serde(serialize_with = "crate::report::serialize_path")]
+    pub path: PathBuf,
+    /// Records in the file (not including any header)
+    pub lines: usize,
+    /// Bytes in the records (not including delimiters)
+    pub bytes: u64,
+    /// The size of the file on disk
+    pub size: u64,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct TreeStats {
+    pub file_count: usize,
+    pub line_count: usize,
+    pub bytes: u64,
+    pub size: u64,
+    pub files: Vec<FileStats>,
+}
+
+/// Count the records and bytes in each file of an output directory
+pub fn stats<F: RuntimeFormat, P: AsRef<Path>>(
+    format: &F,
+    base: P,
+    record_delimiter: Option<RecordDelimiter>,
+) -> Result<TreeStats, Error<F::Error>> {
+    let delimiter = record_delimiter.unwrap_or_else(|| format.record_delimiter());
+    let mut stats = TreeStats::default();
+
+    for file in output_files(base)? {
+        let mut file_stats = FileStats {
+            path: file.relative.clone(),
+            lines: 0,
+            bytes: 0,
+            size: std::fs::metadata(&file.path)?.len(),
+        };
+
+        for record in file.records(format, delimiter)? {
+            file_stats.lines += 1;
+            file_stats.bytes += record?.bytes.len() as u64;
+        }
+
+        stats.file_count += 1;
+        stats.line_count += file_stats.lines;
+        stats.bytes += file_stats.bytes;
+        stats.size += file_stats.size;
+        stats.files.push(file_stats);
+    }
+
+    Ok(stats)
+}
+
+/// A line found in an output directory
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Found {
+    #[serde(serialize_with = "crate::report::serialize_lossy")]
+    pub line: Vec<u8>,
+    pub location: Location,
+}
+
+/// Read the header of the first file in an output directory, if the format has headers
+///
+/// Formats that key lines by the columns named in their header need it before any key or path
+/// is computed.
+pub fn read_header<F: RuntimeFormat, P: AsRef<Path>>(
+    format: &F,
+    base: P,
+    record_delimiter: Option<RecordDelimiter>,
+) -> Result<(), Error<F::Error>> {
+    if !format.has_header() {
+        return Ok(());
+    }
+
+    let delimiter = record_delimiter.unwrap_or_else(|| format.record_delimiter());
+
+    if let Some(file) = output_files(base)?.first() {
+        drop(file.records(format, delimiter)?);
+    }
+
+    Ok(())
+}
+
+/// Find the line for a key in an output directory
+///
+/// The header of an output file is read first (see [`read_header`]). Then only the file that
+/// the format's path gives for the key is read, and reading stops at the first greater key.
+pub fn query<F: RuntimeFormat, P: AsRef<Path>>(
+    format: &F,
+    base: P,
+    record_delimiter: Option<RecordDelimiter>,
+    key: &[u8],
+) -> Result<Option<Found>, Error<F::Error>> {
+    let base = base.as_ref();
+    read_header(format, base, record_delimiter)?;
+
+    let delimiter = record_delimiter.unwrap_or_else(|| format.record_delimiter());
+    let path = format.path(key).map_err(Error::Format)?;
+
+    let plain = base.join(&path);
+    let compressed = base.join(crate::store::output_path(&path, Some(0)));
+
+    let file = if plain.is_file() {
+        OutputFile::new(base, plain)
+    } else if compressed.is_file() {
+        OutputFile::new(base, compressed)
+    } else {
+        return Ok(None);
+    };
+
+    for result in file.keyed(format, delimiter)? {
+        let (line_key, line, location) = result?;
+
+        match line_key.as_slice().cmp(key) {
+            Ordering::Less => {}
+            Ordering::Equal => return Ok(Some(Found { line, location })),
+            Ordering::Greater => break,
+        }
+    }
+
+    Ok(None)
+}
+
+/// A key whose line is not the same in two output directories
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Difference {
+    /// The key is only in the left directory
+    Removed(Found),
+    /// The key is only in the right directory
+    Added(Found),
+    Changed {
+        old: Found,
+        new: Found,
+    },
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct DiffReport {
+    pub removed: usize,
+    pub added: usize,
+    pub changed: usize,
+    pub unchanged: usize,
+}
+
+impl DiffReport {
+    pub fn is_empty(&self) -> bool {
+        self.removed == 0 && self.added == 0 && self.changed == 0
+    }
+}
+
+/// Compare two output directories key by key
+///
+/// Both directories must be sorted and partitioned by the format (see [`crate::check::check`]).
+/// Differences are passed to the callback in path and key order.
+pub fn diff<F: RuntimeFormat, P: AsRef<Path>, Q: AsRef<Path>, C: FnMut(Difference)>(
+    format: &F,
+    left: P,
+    right: Q,
+    record_delimiter: Option<RecordDelimiter>,
+    mut on_difference: C,
+) -> Result<DiffReport, Error<F::Error>> {
+    let delimiter = record_delimiter.unwrap_or_else(|| format.record_delimiter());
+    let mut files = BTreeMap::<PathBuf, (Option<OutputFile>, Option<OutputFile>)>::new();
+
+    for file in output_files(left)? {
+        let entry = files.entry(file.relative.clone()).or_default();
+        entry.0 = Some(file);
+    }
+
+    for file in output_files(right)? {
+        let entry = files.entry(file.relative.clone()).or_default();
+        entry.1 = Some(file);
+    }
+
+    let mut report = DiffReport::default();
+
+    for (left, right) in files.into_values() {
+        let mut left = keyed_or_empty(format, left.as_ref(), delimiter)?.peekable();
+        let mut right = keyed_or_empty(format, right.as_ref(), delimiter)?.peekable();
+
+        while let Some(ordering) = compare_next(&mut left, &mut right)? {
+            match ordering {
+                Ordering::Less => {
+                    let (_, line, location) = left.next().ok_or(Error::InvalidState)??;
+                    report.removed += 1;
+                    on_difference(Difference::Removed(Found { line, location }));
+                }
+                Ordering::Greater => {
+                    let (_, line, location) = right.next().ok_or(Error::InvalidState)??;
+                    report.added += 1;
+                    on_difference(Difference::Added(Found { line, location }));
+                }
+                Ordering::Equal => {
+                    let (_, old_line, old_location) = left.next().ok_or(Error::InvalidState)??;
+                    let (_, new_line, new_location) = right.next().ok_or(Error::InvalidState)??;
+
+                    if old_line == new_line {
+                        report.unchanged += 1;
+                    } else {
+                        report.changed += 1;
+                        on_difference(Difference::Changed {
+                            old: Found {
+                                line: old_line,
+                                location: old_location,
+                            },
+                            new: Found {
+                                line: new_line,
+                                location: new_location,
+                            },
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+fn keyed_or_empty<'a, F: RuntimeFormat>(
+    format: &'a F,
+    file: Option<&OutputFile>,
+    delimiter: RecordDelimiter,
+) -> Result<KeyedRecords<'a, F::Error>, Error<F::Error>> {
+    Ok(match file {
+        Some(file) => Box::new(file.keyed(format, delimiter)?),
+        None => Box::new(std::iter::empty()),
+    })
+}
+
+/// Compare the next keys of two keyed iterators, where a missing key sorts last
+fn compare_next<E, L: Iterator<Item = KeyedResult<E>>, R: Iterator<Item = KeyedResult<E>>>(
+    left: &mut Peekable<L>,
+    right: &mut Peekable<R>,
+) -> Result<Option<Ordering>, Error<E>> {
+    if let Some(Err(_)) = left.peek() {
+        return Err(left
+            .next()
+            .and_then(Result::err)
+            .ok_or(Error::InvalidState)?);
+    }
+
+    if let Some(Err(_)) = right.peek() {
+        return Err(right
+            .next()
+            .and_then(Result::err)
+            .ok_or(Error::InvalidState)?);
+    }
+
+    Ok(match (left.peek(), right.peek()) {
+        (Some(Ok((left, _, _))), Some(Ok((right, _, _)))) => Some(left.cmp(right)),
+        (Some(_), None) => Some(Ordering::Less),
+        (None, Some(_)) => Some(Ordering::Greater),
+        _ => None,
+    })
+}
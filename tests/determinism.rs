@@ -0,0 +1,235 @@
+//! Sessions that resolve repeated keys in input order must not depend on parallelism
+
+use rearranger::Static;
+use rearranger::policy::{CollisionPolicy, Precedence};
+use rearranger::report::RepeatSink;
+use rearranger::session::Session;
+use rearranger::store::Backend;
+use std::path::{Path, PathBuf};
+
+const FILE_COUNT: usize = 12;
+const LINE_COUNT: usize = 500;
+
+/// Tab-separated lines keyed by their first field, with a file for each first character
+struct Tab;
+
+impl rearranger::Format for Tab {
+    type Error = std::convert::Infallible;
+
+    fn key(line: &str) -> Result<Vec<u8>, Self::Error> {
+        Ok(line
+            .split('\t')
+            .next()
+            .unwrap_or_default()
+            .as_bytes()
+            .to_vec())
+    }
+
+    fn path(key: &[u8]) -> Result<PathBuf, Self::Error> {
+        Ok(PathBuf::from(format!("{}.txt", key[0] as char)))
+    }
+}
+
+/// The output of a session: its files, repeat counts, and repeats file
+#[derive(Debug, Eq, PartialEq)]
+struct Outcome {
+    files: Vec<(PathBuf, String)>,
+    duplicates: usize,
+    collisions: usize,
+    repeats: String,
+}
+
+/// Write inputs in which many keys repeat, with both equal and different values
+fn write_inputs(dir: &Path) -> Vec<PathBuf> {
+    (0..FILE_COUNT)
+        .map(|file_index| {
+            let path = dir.join(format!("input-{file_index:02}.txt"));
+            let contents = (0..LINE_COUNT)
+                .map(|line_number| {
+                    let n = file_index * LINE_COUNT + line_number;
+                    let key = ["a", "b", "c"][n % 3].to_string() + &(n % 37).to_string();
+                    format!("{key}\t{}\n", "v".repeat((n / 7) % 3 + 1))
+                })
+                .collect::<String>();
+
+            std::fs::write(&path, contents).unwrap();
+            path
+        })
+        .collect()
+}
+
+async fn run(
+    dir: &Path,
+    name: &str,
+    inputs: &[PathBuf],
+    backend: Backend,
+    policy: CollisionPolicy,
+    precedence: Precedence,
+    parallelism: usize,
+) -> Outcome {
+    let output = dir.join(name).join("output");
+    let repeats = dir.join(name).join("repeats.jsonl.zst");
+    std::fs::create_dir_all(&output).unwrap();
+
+    let report = Session::builder(Static::<Tab>::new())
+        .with_inputs(inputs.to_vec())
+        .with_output(&output)
+        .with_temp_base(dir)
+        .with_backend(backend)
+        .with_parallelism(parallelism)
+        .with_collision_policy(policy)
+        .with_precedence(precedence)
+        .with_repeats(RepeatSink::File(repeats.clone()))
+        .build()
+        .unwrap()
+        .run()
+        .await
+        .unwrap();
+
+    let mut files = std::fs::read_dir(&output)
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let contents = std::fs::read_to_string(&path).unwrap();
+            (path.strip_prefix(&output).unwrap().to_path_buf(), contents)
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+
+    Outcome {
+        files,
+        duplicates: report.duplicates,
+        collisions: report.collisions,
+        repeats: String::from_utf8(
+            zstd::decode_all(std::fs::File::open(repeats).unwrap()).unwrap(),
+        )
+        .unwrap(),
+    }
+}
+
+fn backends() -> Vec<(&'static str, Backend)> {
+    vec![
+        #[cfg(feature = "rocksdb")]
+        ("rocksdb", Backend::default()),
+        #[cfg(feature = "rocksdb")]
+        (
+            "rocksdb-bulk",
+            Backend::RocksDb {
+                database: None,
+                engine: rearranger::db::Engine::Bulk,
+                options: Default::default(),
+            },
+        ),
+        ("merge-sort", Backend::MergeSort),
+        ("memory", Backend::Memory),
+    ]
+}
+
+fn policies() -> Vec<(&'static str, CollisionPolicy)> {
+    vec![
+        ("first", CollisionPolicy::KeepFirst),
+        ("last", CollisionPolicy::KeepLast),
+        ("longest", CollisionPolicy::KeepLongest),
+        // Appending is neither commutative nor idempotent
+        (
+            "merge",
+            CollisionPolicy::merge(|old, new| {
+                let mut merged = old.to_vec();
+                merged.push(b',');
+                merged.extend(new.rsplit(|byte| *byte == b'\t').next().unwrap_or_default());
+                merged
+            }),
+        ),
+    ]
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn output_does_not_depend_on_parallelism() {
+    let dir = tempdir::TempDir::new("determinism").unwrap();
+    let inputs = write_inputs(dir.path());
+    let mut expected_outputs: Vec<(&str, Outcome)> = vec![];
+
+    for (policy_name, policy) in policies() {
+        let mut expected: Option<Outcome> = None;
+
+        for (backend_name, backend) in backends() {
+            for parallelism in [1, 8] {
+                let name = format!("{policy_name}-{backend_name}-{parallelism}");
+                let outcome = run(
+                    dir.path(),
+                    &name,
+                    &inputs,
+                    backend.clone(),
+                    policy.clone(),
+                    Precedence::Input,
+                    parallelism,
+                )
+                .await;
+
+                match expected.as_ref() {
+                    Some(expected) => assert_eq!(&outcome, expected, "{name}"),
+                    None => expected = Some(outcome),
+                }
+            }
+        }
+
+        expected_outputs.extend(expected.map(|expected| (policy_name, expected)));
+    }
+
+    // Make sure that the policies are actually exercised
+    for (index, (name, outcome)) in expected_outputs.iter().enumerate() {
+        assert!(outcome.duplicates > 0 && outcome.collisions > 0, "{name}");
+
+        for (other_name, other) in &expected_outputs[index + 1..] {
+            assert_ne!(outcome.files, other.files, "{name} and {other_name}");
+        }
+    }
+}
+
+/// With a single reader, resolving repeats on arrival in transactions must match the bulk engine
+#[cfg(feature = "rocksdb")]
+#[tokio::test(flavor = "multi_thread")]
+async fn transactions_in_arrival_order_match_bulk() {
+    let dir = tempdir::TempDir::new("determinism").unwrap();
+    let inputs = write_inputs(dir.path());
+
+    for (policy_name, policy) in policies() {
+        let mut outcomes = vec![];
+
+        for (engine_name, engine, precedence) in [
+            (
+                "transaction",
+                rearranger::db::Engine::Transaction,
+                Precedence::Arrival,
+            ),
+            ("bulk", rearranger::db::Engine::Bulk, Precedence::Input),
+        ] {
+            let backend = Backend::RocksDb {
+                database: None,
+                engine,
+                options: Default::default(),
+            };
+            let name = format!("{policy_name}-{engine_name}");
+            let mut outcome = run(
+                dir.path(),
+                &name,
+                &inputs,
+                backend,
+                policy.clone(),
+                precedence,
+                1,
+            )
+            .await;
+
+            // Transactions report repeats as they are found, and the bulk engine as it merges
+            let mut repeats = outcome.repeats.lines().collect::<Vec<_>>();
+            repeats.sort_unstable();
+            outcome.repeats = repeats.join("\n");
+
+            outcomes.push(outcome);
+        }
+
+        assert!(outcomes[0].collisions > 0, "{policy_name}");
+        assert_eq!(outcomes[0], outcomes[1], "{policy_name}");
+    }
+}